@@ -94,7 +94,29 @@ pub enum HoldTapConfig {
     /// value will cause a fallback to the timeout-based approach. If the
     /// timeout is not triggered, the next tick will call the custom handler
     /// again.
-    Custom(fn(StackedIter) -> Option<WaitingAction>),
+    ///
+    /// The handler can be a closure, e.g. one that captures the keys it
+    /// should react to.
+    Custom(&'static (dyn Fn(StackedIter) -> Option<WaitingAction> + Send + Sync)),
+    /// Resolve by the hand that types the other keys, which is useful
+    /// for home row mods.
+    ///
+    /// The handler returns the [Hand] of the key at a coordinate, or
+    /// [None] for keys of neither hand. A press of a key of the same
+    /// hand as the HoldTap key activates the tap action, and a press
+    /// of a key of the other hand activates the hold action. Keys of
+    /// neither hand act like [PermissiveHold](HoldTapConfig::PermissiveHold):
+    /// pressing and releasing one activates the hold action.
+    OppositeHand(&'static (dyn Fn((u8, u16)) -> Option<Hand> + Send + Sync)),
+}
+
+/// The hand that types a key, used by [HoldTapConfig::OppositeHand].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Hand {
+    /// The left hand.
+    Left,
+    /// The right hand.
+    Right,
 }
 
 impl Debug for HoldTapConfig {
@@ -105,12 +127,19 @@ impl Debug for HoldTapConfig {
             HoldTapConfig::PermissiveHold => f.write_str("PermissiveHold"),
             HoldTapConfig::Custom(func) => f
                 .debug_tuple("Custom")
-                .field(&(*func as fn(StackedIter<'static>) -> Option<WaitingAction>) as &dyn Debug)
+                .field(&(*func as *const _ as *const ()))
+                .finish(),
+            HoldTapConfig::OppositeHand(func) => f
+                .debug_tuple("OppositeHand")
+                .field(&(*func as *const _ as *const ()))
                 .finish(),
         }
     }
 }
 
+/// Handlers are compared by address only, since the vtable of the same
+/// handler can differ between codegen units. Zero-sized handlers, like
+/// references to functions, can share an address and then compare equal.
 impl PartialEq for HoldTapConfig {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -118,7 +147,10 @@ impl PartialEq for HoldTapConfig {
             | (HoldTapConfig::HoldOnOtherKeyPress, HoldTapConfig::HoldOnOtherKeyPress)
             | (HoldTapConfig::PermissiveHold, HoldTapConfig::PermissiveHold) => true,
             (HoldTapConfig::Custom(self_func), HoldTapConfig::Custom(other_func)) => {
-                *self_func as fn(StackedIter<'static>) -> Option<WaitingAction> == *other_func
+                core::ptr::addr_eq(*self_func, *other_func)
+            }
+            (HoldTapConfig::OppositeHand(self_func), HoldTapConfig::OppositeHand(other_func)) => {
+                core::ptr::addr_eq(*self_func, *other_func)
             }
             _ => false,
         }
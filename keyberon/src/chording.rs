@@ -60,7 +60,7 @@ impl Chord {
 
     fn contains_chord(&mut self, events: &[Event]) -> bool {
         for key in self.def.1 {
-            if !events.iter().any(|&k| (&k.coord() == key && k.is_press())) {
+            if !events.iter().any(|&k| &k.coord() == key && k.is_press()) {
                 return false;
             }
         }
@@ -72,7 +72,7 @@ impl Chord {
         for key in self.def.1 {
             if let Some(position) = events
                 .iter()
-                .position(|&k| (&k.coord() == key && k.is_press()))
+                .position(|&k| &k.coord() == key && k.is_press())
             {
                 events.swap_remove(position);
             }
@@ -87,7 +87,7 @@ impl Chord {
             for key in self.def.1 {
                 if let Some(position) = events
                     .iter()
-                    .position(|&k| (&k.coord() == key && k.is_release()))
+                    .position(|&k| &k.coord() == key && k.is_release())
                 {
                     events.swap_remove(position);
                 }
@@ -112,6 +112,14 @@ pub struct Chording<const N: usize> {
 impl<const N: usize> Chording<N> {
     /// Take the predefined chord list in.
     pub fn new(chords: &'static [ChordDef; N]) -> Self {
+        Self::from_slice(chords)
+    }
+
+    /// Take a chord list whose length is only known at runtime in.
+    ///
+    /// Panics if the list contains more than `N` chords.
+    pub fn from_slice(chords: &'static [ChordDef]) -> Self {
+        assert!(chords.len() <= N, "too many chords for chording capacity");
         Self {
             chords: chords.iter().map(Chord::new).collect(),
         }
@@ -257,4 +265,19 @@ mod test {
         double_press.push(Press(0, 2)).ok();
         assert_eq!(chording.tick(double_press), &[Press(1, 2)]);
     }
+
+    #[test]
+    fn chord_from_slice() {
+        static CHORDS: [ChordDef; 2] = [((1, 0), &[(0, 0), (0, 1)]), ((1, 1), &[(0, 1), (0, 2)])];
+        let mut chording = Chording::<4>::from_slice(&CHORDS[..]);
+
+        let mut double_press = Vec::<Event, 8>::new();
+        double_press.push(Press(0, 1)).ok();
+        double_press.push(Press(0, 2)).ok();
+        assert_eq!(chording.tick(double_press), &[Press(1, 1)]);
+        let mut double_release = Vec::<Event, 8>::new();
+        double_release.push(Release(0, 1)).ok();
+        double_release.push(Release(0, 2)).ok();
+        assert_eq!(chording.tick(double_release), &[Release(1, 1)]);
+    }
 }
@@ -49,11 +49,16 @@ type Stack = ArrayDeque<[Stacked; 16], arraydeque::behavior::Wrapping>;
 
 /// The layout manager. It takes `Event`s and `tick`s as input, and
 /// generate keyboard reports.
-pub struct Layout<const C: usize, const R: usize, const L: usize, T = core::convert::Infallible>
+pub struct Layout<const C: usize, T = core::convert::Infallible>
 where
     T: 'static,
 {
-    pub layers: &'static [[[Action<T>; C]; R]; L],
+    /// The rows of all layers, one layer after the other.
+    pub layers: &'static [[Action<T>; C]],
+    /// The number of rows of each layer.
+    pub rows: usize,
+    /// Actions of transparent keys on the default layer. Without it, they do nothing.
+    pub src_layer: Option<&'static [[Action<T>; C]]>,
     pub default_layer: usize,
     /// Key states.
     pub states: Vec<State<T>, 64>,
@@ -309,6 +314,30 @@ impl<T> WaitingState<T> {
                     return waiting_action;
                 }
             }
+            HoldTapConfig::OppositeHand(hand_of) => {
+                let hand = hand_of(self.coord);
+                for (x, s) in stacked.iter().enumerate() {
+                    if self.is_corresponding_release(&s.event) {
+                        break;
+                    }
+                    let (i, j) = s.event.coord();
+                    if s.event.is_press() {
+                        match (hand, hand_of((i, j))) {
+                            (Some(hand), Some(other)) if hand == other => {
+                                return Some(WaitingAction::Tap)
+                            }
+                            (Some(_), Some(_)) => return Some(WaitingAction::Hold),
+                            _ => {}
+                        }
+                    } else {
+                        // A key of neither hand was pressed and released.
+                        let target = Event::Press(i, j);
+                        if stacked.iter().take(x).any(|s| s.event == target) {
+                            return Some(WaitingAction::Hold);
+                        }
+                    }
+                }
+            }
         }
         if let Some(&Stacked { since, .. }) = stacked
             .iter()
@@ -515,11 +544,18 @@ impl TapHoldTracker {
     }
 }
 
-impl<const C: usize, const R: usize, const L: usize, T: 'static + Copy> Layout<C, R, L, T> {
+impl<const C: usize, T: 'static + Copy> Layout<C, T> {
     /// Creates a new `Layout` object.
-    pub fn new(layers: &'static [[[Action<T>; C]; R]; L]) -> Self {
+    pub fn new<const R: usize>(layers: &'static [[[Action<T>; C]; R]]) -> Self {
+        Self::new_from_rows(layers.as_flattened(), R)
+    }
+    /// Creates a new `Layout` object from the rows of all layers, `rows` rows per layer, for
+    /// layouts whose number of rows is only known at runtime.
+    pub fn new_from_rows(layers: &'static [[Action<T>; C]], rows: usize) -> Self {
         Self {
             layers,
+            rows,
+            src_layer: None,
             default_layer: 0,
             states: Vec::new(),
             waiting: None,
@@ -535,6 +571,18 @@ impl<const C: usize, const R: usize, const L: usize, T: 'static + Copy> Layout<C
             active_sequences: ArrayDeque::new(),
         }
     }
+    /// Creates a new `Layout` object from the rows of all layers like `new_from_rows`, with as
+    /// many rows per layer as `src_layer` has. Transparent keys on the default layer act like the
+    /// key at the same position in `src_layer`.
+    pub fn new_with_src_layer(
+        layers: &'static [[Action<T>; C]],
+        src_layer: &'static [[Action<T>; C]],
+    ) -> Self {
+        Self {
+            src_layer: Some(src_layer),
+            ..Self::new_from_rows(layers, src_layer.len())
+        }
+    }
     /// Iterates on the key codes of the current state.
     pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
         self.states.iter().filter_map(State::keycode)
@@ -738,7 +786,7 @@ impl<const C: usize, const R: usize, const L: usize, T: 'static + Copy> Layout<C
         use crate::action::Action::*;
         let action = self
             .layers
-            .get(layer)
+            .get(layer * self.rows..(layer + 1) * self.rows)
             .and_then(|l| l.get(coord.0 as usize))
             .and_then(|l| l.get(coord.1 as usize));
         match action {
@@ -747,7 +795,10 @@ impl<const C: usize, const R: usize, const L: usize, T: 'static + Copy> Layout<C
                 if layer != self.default_layer {
                     self.press_as_action(coord, self.default_layer)
                 } else {
-                    &NoOp
+                    self.src_layer
+                        .and_then(|l| l.get(coord.0 as usize))
+                        .and_then(|l| l.get(coord.1 as usize))
+                        .unwrap_or(&NoOp)
                 }
             }
             Some(action) => action,
@@ -894,7 +945,7 @@ impl<const C: usize, const R: usize, const L: usize, T: 'static + Copy> Layout<C
 
     /// Sets the default layer for the layout
     pub fn set_default_layer(&mut self, value: usize) {
-        if value < self.layers.len() {
+        if value < self.layers.len().checked_div(self.rows).unwrap_or(0) {
             self.default_layer = value
         }
     }
@@ -905,8 +956,8 @@ mod test {
     extern crate std;
     use super::{Event::*, Layout, *};
     use crate::action::Action::*;
-    use crate::action::HoldTapConfig;
     use crate::action::{k, l, m};
+    use crate::action::{Hand, HoldTapConfig};
     use crate::key_code::KeyCode;
     use crate::key_code::KeyCode::*;
     use std::collections::BTreeSet;
@@ -1111,6 +1162,75 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn opposite_hand() {
+        fn hand_of((_, j): (u8, u16)) -> Option<Hand> {
+            match j {
+                0 | 1 => Some(Hand::Left),
+                2 => Some(Hand::Right),
+                _ => None,
+            }
+        }
+        static LAYERS: Layers<4, 1, 1> = [[[
+            HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: k(LAlt),
+                tap: k(A),
+                config: HoldTapConfig::OppositeHand(&hand_of),
+                tap_hold_interval: 0,
+            }),
+            k(S),
+            k(L),
+            k(Space),
+        ]]];
+        let mut layout = Layout::new(&LAYERS);
+
+        // A key of the same hand taps
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[A], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[A, S], layout.keycodes());
+        layout.event(Release(0, 0));
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // A key of the other hand holds
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 2));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt, L], layout.keycodes());
+        layout.event(Release(0, 2));
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // A key of neither hand holds once it is released
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 3));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        layout.event(Release(0, 3));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt, Space], layout.keycodes());
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+    }
+
     #[test]
     fn multiple_actions() {
         static LAYERS: Layers<2, 1, 2> = [
@@ -1134,6 +1254,25 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn trans_on_default_layer_uses_src_layer() {
+        static LAYERS: Layers<2, 1, 2> = [[[Trans, l(1)]], [[Trans, Trans]]];
+        static SRC_LAYER: [[Action; 2]; 1] = [[k(A), k(B)]];
+        let mut layout = Layout::new_with_src_layer(LAYERS.as_flattened(), &SRC_LAYER);
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[A], layout.keycodes());
+        layout.event(Release(0, 0));
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        // Transparent on layer 1 and then on the default layer.
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[A], layout.keycodes());
+    }
+
     #[test]
     fn custom() {
         static LAYERS: Layers<1, 1, 1, u8> = [[[Action::Custom(42)]]];
@@ -1219,6 +1358,28 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn hold_tap_configs_compare_handlers() {
+        // Like the handlers created at runtime, these capture data so that each one has its own
+        // address.
+        fn handler(
+            action: WaitingAction,
+        ) -> &'static (dyn Fn(StackedIter) -> Option<WaitingAction> + Send + Sync) {
+            Box::leak(Box::new(move |_: StackedIter| Some(action)))
+        }
+        fn hand(hand: Hand) -> &'static (dyn Fn((u8, u16)) -> Option<Hand> + Send + Sync) {
+            Box::leak(Box::new(move |_: (u8, u16)| Some(hand)))
+        }
+        let tap = HoldTapConfig::Custom(handler(WaitingAction::Tap));
+        assert_eq!(tap, tap);
+        assert_ne!(tap, HoldTapConfig::Custom(handler(WaitingAction::Tap)));
+        assert_ne!(tap, HoldTapConfig::Custom(handler(WaitingAction::Hold)));
+        let left_hand = HoldTapConfig::OppositeHand(hand(Hand::Left));
+        assert_eq!(left_hand, left_hand);
+        assert_ne!(left_hand, HoldTapConfig::OppositeHand(hand(Hand::Right)));
+        assert_ne!(left_hand, HoldTapConfig::PermissiveHold);
+    }
+
     #[test]
     fn custom_handler() {
         fn always_tap(_: StackedIter) -> Option<WaitingAction> {
@@ -1238,28 +1399,28 @@ mod test {
                 timeout: 200,
                 hold: k(Kb1),
                 tap: k(Kb0),
-                config: HoldTapConfig::Custom(always_tap),
+                config: HoldTapConfig::Custom(&always_tap),
                 tap_hold_interval: 0,
             }),
             HoldTap(&HoldTapAction {
                 timeout: 200,
                 hold: k(Kb3),
                 tap: k(Kb2),
-                config: HoldTapConfig::Custom(always_hold),
+                config: HoldTapConfig::Custom(&always_hold),
                 tap_hold_interval: 0,
             }),
             HoldTap(&HoldTapAction {
                 timeout: 200,
                 hold: k(Kb5),
                 tap: k(Kb4),
-                config: HoldTapConfig::Custom(always_nop),
+                config: HoldTapConfig::Custom(&always_nop),
                 tap_hold_interval: 0,
             }),
             HoldTap(&HoldTapAction {
                 timeout: 200,
                 hold: k(Kb7),
                 tap: k(Kb6),
-                config: HoldTapConfig::Custom(always_none),
+                config: HoldTapConfig::Custom(&always_none),
                 tap_hold_interval: 0,
             }),
         ]]];
@@ -0,0 +1,168 @@
+//! Allocations of a configuration. The keyberon layout needs `'static` references to its actions,
+//! so they are allocated here and freed only when everything that references them is dropped,
+//! e.g. when a live reload replaces the configuration.
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// A type-erased allocation made by `sref`.
+struct Allocation {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+// SAFETY: `ptr` is only created by `sref` from a `Box<T>` with `T: Send + Sync`, so the value may
+// be dropped on another thread and shared between threads. `Allocation` never hands out the
+// pointer; it is only used to drop the value once, when `Allocations` is dropped.
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+unsafe fn drop_box<T>(ptr: *mut ()) {
+    drop(unsafe { Box::from_raw(ptr as *mut T) });
+}
+
+thread_local! {
+    /// The allocations of the configuration that is currently being parsed on this thread.
+    static CURRENT: RefCell<Option<Vec<Allocation>>> = const { RefCell::new(None) };
+}
+
+/// Owns the allocations made by `sref` while parsing a configuration and frees them when dropped.
+struct Allocations(Vec<Allocation>);
+
+impl Drop for Allocations {
+    fn drop(&mut self) {
+        for a in self.0.drain(..) {
+            unsafe { (a.drop)(a.ptr) };
+        }
+    }
+}
+
+/// A value that references allocations made by `sref`, along with those allocations. The
+/// allocations are freed once every `Allocated` that shares them is dropped, and a value can only
+/// be replaced along with the allocations that it references. The `'static` references in the
+/// value are only valid for as long as the `Allocated` lives, so they must not be kept after it is
+/// dropped.
+pub struct Allocated<T> {
+    // Fields are dropped in declaration order, so the value is dropped before its allocations.
+    value: T,
+    _allocations: Arc<Allocations>,
+}
+
+impl<T> Allocated<T> {
+    /// Wrap a value that references the allocations of `handle`.
+    pub(super) fn new(value: T, handle: &AllocationsHandle) -> Self {
+        Self {
+            value,
+            _allocations: handle.0.clone(),
+        }
+    }
+
+    /// A weak reference to the allocations, to check when they are freed.
+    #[cfg(test)]
+    pub(crate) fn allocations(&self) -> std::sync::Weak<impl Sized> {
+        Arc::downgrade(&self._allocations)
+    }
+}
+
+impl<T> Deref for Allocated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Allocated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// The allocations that `collect` returns, used to create the `Allocated` values that reference
+/// them.
+pub(super) struct AllocationsHandle(Arc<Allocations>);
+
+/// Puts back the allocations of an enclosing `collect` when dropped, so that they are restored
+/// even if `f` panics. The allocations made before a panic are leaked, since values that reference
+/// them may still exist.
+struct RestoreCurrent(Option<Vec<Allocation>>);
+
+impl Drop for RestoreCurrent {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Run `f` and return its result along with the allocations that `sref` made while it ran.
+pub(super) fn collect<R>(f: impl FnOnce() -> R) -> (R, AllocationsHandle) {
+    let restore = RestoreCurrent(CURRENT.with(|c| c.replace(Some(vec![]))));
+    let res = f();
+    let allocations = CURRENT.with(|c| c.take()).unwrap_or_default();
+    drop(restore);
+    (res, AllocationsHandle(Arc::new(Allocations(allocations))))
+}
+
+/// Returns a `&'static T` for `v`. Inside of `collect`, `v` is freed along with the allocations
+/// that `collect` returns. Otherwise it is leaked.
+///
+/// # Safety
+///
+/// Inside of `collect`, the returned reference must not be used after the allocations that
+/// `collect` returns are freed. It must only be kept in the result of `collect`, in values that
+/// are then wrapped in an `Allocated` created from those allocations.
+pub(super) unsafe fn sref<T: Send + Sync + 'static>(v: T) -> &'static T {
+    let ptr = Box::into_raw(Box::new(v));
+    CURRENT.with(|c| {
+        if let Some(allocations) = c.borrow_mut().as_mut() {
+            allocations.push(Allocation {
+                ptr: ptr as *mut (),
+                drop: drop_box::<T>,
+            });
+        }
+    });
+    unsafe { &*ptr }
+}
+
+#[test]
+fn allocations_are_freed_on_drop() {
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct CountDrops;
+    impl Drop for CountDrops {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, SeqCst);
+        }
+    }
+
+    let (r, handle) = collect(|| unsafe {
+        let r = sref(vec![1, 2, 3]).as_slice();
+        sref(CountDrops);
+        sref(CountDrops);
+        r
+    });
+    let a = Allocated::new(r, &handle);
+    let b = Allocated::new(r, &handle);
+    drop(handle);
+    assert_eq!(*a, &[1, 2, 3]);
+    assert_eq!(DROPS.load(SeqCst), 0);
+    // The allocations live as long as any value that references them.
+    drop(a);
+    assert_eq!(*b, &[1, 2, 3]);
+    assert_eq!(DROPS.load(SeqCst), 0);
+    drop(b);
+    assert_eq!(DROPS.load(SeqCst), 2);
+}
+
+#[test]
+fn collect_restores_the_enclosing_allocations_on_panic() {
+    let (_, handle) = collect(|| {
+        let res = std::panic::catch_unwind(|| collect(|| panic!("parsing failed")));
+        assert!(res.is_err());
+        unsafe { sref(1) };
+    });
+    // The allocation made after the panic belongs to the outer `collect`.
+    assert_eq!(handle.0 .0.len(), 1);
+    assert!(CURRENT.with(|c| c.borrow().is_none()));
+}
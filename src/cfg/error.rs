@@ -0,0 +1,122 @@
+//! Errors that point at the part of the configuration that caused them, so that they can be
+//! reported with a line, column and snippet of the configuration.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use super::sexpr::{self, ParseError, Span, Spanned};
+
+/// Return early with an error about the part of the configuration at the given span.
+macro_rules! bail_span {
+    ($span:expr, $($arg:tt)*) => {
+        return Err(SpannedError {
+            msg: format!($($arg)*),
+            span: $span,
+        }
+        .into())
+    };
+}
+
+/// An error caused by the part of the configuration at `span`.
+#[derive(Debug)]
+pub struct SpannedError {
+    pub msg: String,
+    pub span: Span,
+}
+
+impl fmt::Display for SpannedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for SpannedError {}
+
+/// Independent errors found in the configuration, which are reported together.
+#[derive(Debug)]
+pub struct CfgErrors(pub Vec<anyhow::Error>);
+
+impl fmt::Display for CfgErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msgs: Vec<_> = self.0.iter().map(|e| format!("{e:#}")).collect();
+        write!(f, "{}", msgs.join("\n"))
+    }
+}
+
+impl std::error::Error for CfgErrors {}
+
+/// Make `e` point at `span` unless it already points at a more specific part of the
+/// configuration.
+pub fn spanned(e: anyhow::Error, span: Span) -> anyhow::Error {
+    if e.is::<SpannedError>() || e.is::<CfgErrors>() {
+        return e;
+    }
+    SpannedError {
+        msg: format!("{e:#}"),
+        span,
+    }
+    .into()
+}
+
+/// Returns an error containing all of `errors`, if there are any.
+pub fn combine(errors: Vec<anyhow::Error>) -> Result<()> {
+    let mut all = vec![];
+    for e in errors {
+        match e.downcast::<CfgErrors>() {
+            Ok(CfgErrors(errors)) => all.extend(errors),
+            Err(e) => all.push(e),
+        }
+    }
+    match all.len() {
+        0 => Ok(()),
+        1 => Err(all.remove(0)),
+        _ => Err(CfgErrors(all).into()),
+    }
+}
+
+/// Add `e` to `errors` and return an error containing all of them, for when parsing can't go on
+/// after `e`.
+pub fn add_error(errors: &mut Vec<anyhow::Error>, e: anyhow::Error) -> anyhow::Error {
+    errors.push(e);
+    combine(std::mem::take(errors)).unwrap_err()
+}
+
+/// A file of the configuration. Spans with its index as their file id point into its text.
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// Format `e` with the location and snippet of every error in it that points into one of the
+/// configuration's `files`. Errors that don't point anywhere are reported for the first file.
+pub fn format_errors(e: &anyhow::Error, files: &[SourceFile]) -> String {
+    let errors = match e.downcast_ref::<CfgErrors>() {
+        Some(CfgErrors(errors)) => errors.iter().collect(),
+        None => vec![e],
+    };
+    let mut spanned_errors: Vec<Vec<ParseError>> = files.iter().map(|_| vec![]).collect();
+    let mut other_errors = vec![];
+    for e in errors {
+        match e.downcast_ref::<SpannedError>() {
+            Some(SpannedError { msg, span }) if span.file_id() < files.len() => {
+                spanned_errors[span.file_id()].push(Spanned::new(msg.clone(), *span))
+            }
+            _ => other_errors.push(match files.first() {
+                Some(file) => format!("error: {e:#}\n --> {}", file.path.display()),
+                None => format!("error: {e:#}"),
+            }),
+        }
+    }
+    for (file, errors) in files.iter().zip(spanned_errors) {
+        if !errors.is_empty() {
+            other_errors.push(sexpr::pretty_errors(
+                &file.path.to_string_lossy(),
+                &file.text,
+                errors,
+            ));
+        }
+    }
+    other_errors.join("\n\n")
+}
@@ -23,20 +23,25 @@
 //!
 //!     xx means unimportant and _ means transparent.
 //!
-//!     layers[0] = { xx, esc, a, s, d, 4, xx... }
-//!     layers[1] = { xx, _  , a, s, d, _, xx... }
-//!     layers[2] = { xx, esc, a, o, e, 4, xx... }
-//!     layers[3] = { xx, _  , a, s, d, _, xx... }
+//!     layers[0] = { xx, _  , a, s, d, _, xx... }
+//!     layers[1] = { xx, _  , a, o, e, _, xx... }
+//!     layers[2] = { xx, esc, 1, 2, 3, 4, xx... }
 //!
 //! Note that this example isn't practical, but `(defsrc esc 1 2 3 4)` is used because these keys
 //! are at the beginning of the array. The column index for layers is the numerical value of
 //! the key from `keys::OsCode`.
 //!
-//! In addition, there are two versions of each layer. One version delegates transparent entries to
-//! the key defined in defsrc, while the other keeps them as actually transparent. This is to match
-//! the behaviour in kmonad.
+//! In addition, the last layer holds the keys defined in defsrc. Transparent entries of the
+//! default layer act like the key in the last layer, while transparent entries of any other active
+//! layer act like the entry of the default layer. This is to match the behaviour in kmonad.
+//!
+//! The example above only shows the first row of each layer. The second row holds fake keys and
+//! chord outputs, and each `defdevice` block gets one more row for the keys of its own `defsrc`.
 //!
 //! The specific values in example above applies to Linux, but the same logic applies to Windows.
+mod alloc;
+#[macro_use]
+mod error;
 mod sexpr;
 
 use crate::custom_action::*;
@@ -46,20 +51,37 @@ use crate::layers::*;
 use anyhow::{anyhow, bail, Result};
 use radix_trie::Trie;
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 
 type HashSet<T> = rustc_hash::FxHashSet<T>;
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
 
+pub use alloc::Allocated;
+
+// `Cfg::new_from_file` parses inside of `alloc::collect`, and every reference returned by `sref`
+// while parsing is only kept in the layout and the chord groups, which it wraps in `Allocated`
+// along with the allocations. That upholds the contract of `sref` for its uses in this module.
+use alloc::sref;
+use error::*;
 use kanata_keyberon::action::*;
+use kanata_keyberon::chording::*;
 use kanata_keyberon::key_code::*;
 use kanata_keyberon::layout::*;
-use sexpr::SExpr;
+use sexpr::{unquote, SExpr};
 
 use self::sexpr::Spanned;
 
 pub type KanataAction = Action<&'static [&'static CustomAction]>;
-pub type KanataLayout = Layout<KEYS_IN_ROW, 2, ACTUAL_NUM_LAYERS, &'static [&'static CustomAction]>;
+pub type KanataLayout = Layout<KEYS_IN_ROW, &'static [&'static CustomAction]>;
 pub type KeySeqsToFKeys = Trie<Vec<u16>, (u8, u16)>;
+pub type ChordGroups = Vec<ChordGroup>;
+/// Maps `deffakekeys` names to their keyberon coordinates.
+pub type FakeKeys = HashMap<String, (u8, u16)>;
+
+/// Maximum number of chords in a single `defchords` group.
+pub const MAX_CHORDS_IN_GROUP: usize = 64;
+/// Maximum number of keys in a single chord. This is the limit of keyberon's chording engine.
+const MAX_KEYS_IN_CHORD: usize = 8;
 
 pub struct Cfg {
     /// The list of keys that kanata should be processing. Keys that are missing from `mapped_keys`
@@ -74,26 +96,118 @@ pub struct Cfg {
     /// Configuration items in `defcfg`.
     pub items: HashMap<String, String>,
     /// The keyberon layout state machine struct.
-    pub layout: KanataLayout,
+    pub layout: Allocated<KanataLayout>,
     /// Sequences defined in `defseq`.
     pub sequences: KeySeqsToFKeys,
+    /// Chord groups defined in `defchords`.
+    pub chord_groups: Allocated<ChordGroups>,
+    /// Per-device configurations defined in `defdevice`.
+    pub devices: Vec<DeviceCfg>,
+    /// Fake keys defined in `deffakekeys`.
+    pub fake_keys: FakeKeys,
+    /// Text of the aliases and sequences, used to log what a live reload changed.
+    pub def_texts: DefTexts,
+    /// The configuration file and the files that it includes.
+    pub files: Vec<std::path::PathBuf>,
+    /// Names of the configurations that `switch-config` actions switch to.
+    pub switch_configs: Vec<String>,
 }
 
 impl Cfg {
     pub fn new_from_file(p: &std::path::Path) -> Result<Self> {
-        let (items, mapped_keys, layer_info, key_outputs, layout, sequences) = parse_cfg(p)?;
+        // If parsing fails, the allocations of the partially parsed configuration are dropped
+        // along with the error.
+        let (res, allocations) = alloc::collect(|| parse_cfg(p));
+        let ParsedCfg {
+            items,
+            mapped_keys,
+            layer_info,
+            key_outputs,
+            layout,
+            sequences,
+            chord_groups,
+            devices,
+            fake_keys,
+            def_texts,
+            files,
+            switch_configs,
+        } = res?;
         log::info!("config parsed");
         Ok(Self {
             items,
             mapped_keys,
             layer_info,
             key_outputs,
-            layout,
+            layout: Allocated::new(layout, &allocations),
             sequences,
+            chord_groups: Allocated::new(chord_groups, &allocations),
+            devices,
+            fake_keys,
+            def_texts,
+            files,
+            switch_configs,
         })
     }
 }
 
+/// The configuration of a `defdevice` block. Key events from the input devices it matches are
+/// processed with its own `defsrc` and layers instead of the top-level ones.
+pub struct DeviceCfg {
+    /// Name of the block, used for logging.
+    pub name: String,
+    /// A device uses this configuration if any of the matchers match it.
+    pub matchers: Vec<DeviceMatcher>,
+    /// Same as `Cfg::mapped_keys`, but for the devices of this block.
+    pub mapped_keys: MappedKeys,
+    /// Same as `Cfg::key_outputs`, but for the devices of this block.
+    pub key_outputs: KeyOutputs,
+}
+
+/// Describes which input devices a `defdevice` block applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMatcher {
+    /// The name that the device reports.
+    Name(String),
+    /// The device path, e.g. `/dev/input/by-id/...`. Symlinks are resolved before comparing.
+    Path(String),
+    /// The USB vendor and product id of the device.
+    Id { vendor: u16, product: u16 },
+}
+
+/// A group of chords defined in a `defchords` expression. Presses of the keys in a group are held
+/// back for up to `timeout` milliseconds and then run through keyberon's chording engine.
+pub struct ChordGroup {
+    /// Name of the group, used for logging.
+    pub name: String,
+    /// Time in milliseconds to wait for all keys of a chord to be pressed.
+    pub timeout: u16,
+    /// The `OsCode`s of the keys that are part of at least one chord in this group.
+    pub keys: HashSet<u16>,
+    /// Chord definitions in keyberon coordinates.
+    defs: &'static [ChordDef],
+    /// The keyberon chording state machine for this group.
+    pub chording: Chording<MAX_CHORDS_IN_GROUP>,
+}
+
+impl ChordGroup {
+    /// Returns true if the presses in `events` complete a chord of this group and there is no
+    /// larger chord that could still be completed, i.e. waiting for the timeout cannot change the
+    /// result.
+    pub fn is_resolved(&self, events: &[Event]) -> bool {
+        let pressed: Vec<_> = events
+            .iter()
+            .filter(|ev| ev.is_press())
+            .map(|ev| ev.coord())
+            .collect();
+        let is_complete = |keys: &[(u8, u16)]| keys.iter().all(|k| pressed.contains(k));
+        self.defs.iter().any(|(_, keys)| is_complete(keys))
+            && !self
+                .defs
+                .iter()
+                .any(|(_, keys)| !is_complete(keys) && pressed.iter().all(|p| keys.contains(p)))
+    }
+}
+
 pub type MappedKeys = HashSet<OsCode>;
 // Note: this uses a Vec instead of a HashSet because ordering matters, e.g. for chords like `S-b`,
 // we want to ensure that `b` is checked first, so this will be iterated over in reverse order.
@@ -124,9 +238,11 @@ fn parse_default() {
 
 #[test]
 fn parse_jtroo() {
-    let (_, _, layer_strings, _, _, _) =
-        parse_cfg(&std::path::PathBuf::from("./cfg_samples/jtroo.kbd")).unwrap();
-    assert_eq!(layer_strings.len(), 16);
+    let ParsedCfg {
+        layer_info: layer_strings,
+        ..
+    } = parse_cfg(&std::path::PathBuf::from("./cfg_samples/jtroo.kbd")).unwrap();
+    assert_eq!(layer_strings.len(), 8);
 }
 
 #[test]
@@ -136,43 +252,41 @@ fn parse_f13_f24() {
 
 #[test]
 fn parse_transparent_default() {
-    let (_, _, layer_strings, layers, _) = parse_cfg_raw(&std::path::PathBuf::from(
+    let RawCfg {
+        layer_info: layer_strings,
+        layers,
+        ..
+    } = parse_cfg_raw(&std::path::PathBuf::from(
         "./cfg_samples/transparent_default.kbd",
     ))
     .unwrap();
 
-    assert_eq!(layer_strings.len(), 4);
+    assert_eq!(layer_strings.len(), 2);
+    // The layers are followed by the layer that transparent keys on the default layer use.
+    assert_eq!(layers.len(), 3);
+    // Without defdevice, layers only have the defsrc row and the fake key row.
+    assert_eq!(layers.rows_per_layer(), DEVICE_ROWS_START);
 
-    assert_eq!(
-        layers[0][0][usize::from(OsCode::KEY_F13)],
-        Action::KeyCode(KeyCode::F13)
-    );
+    assert_eq!(layers[0][0][usize::from(OsCode::KEY_F13)], Action::Trans);
     assert_eq!(
         layers[0][0][usize::from(OsCode::KEY_F14)],
-        Action::DefaultLayer(2)
+        Action::DefaultLayer(1)
     );
-    assert_eq!(layers[0][0][usize::from(OsCode::KEY_F15)], Action::Layer(3));
-    assert_eq!(layers[1][0][usize::from(OsCode::KEY_F13)], Action::Trans);
+    assert_eq!(layers[0][0][usize::from(OsCode::KEY_F15)], Action::Layer(1));
     assert_eq!(
-        layers[1][0][usize::from(OsCode::KEY_F14)],
-        Action::DefaultLayer(2)
+        layers[1][0][usize::from(OsCode::KEY_F13)],
+        Action::DefaultLayer(0)
     );
-    assert_eq!(layers[1][0][usize::from(OsCode::KEY_F15)], Action::Layer(3));
+    assert_eq!(layers[1][0][usize::from(OsCode::KEY_F14)], Action::Layer(0));
+    assert_eq!(layers[1][0][usize::from(OsCode::KEY_F15)], Action::Trans);
     assert_eq!(
         layers[2][0][usize::from(OsCode::KEY_F13)],
-        Action::DefaultLayer(0)
+        Action::KeyCode(KeyCode::F13)
     );
-    assert_eq!(layers[2][0][usize::from(OsCode::KEY_F14)], Action::Layer(1));
     assert_eq!(
         layers[2][0][usize::from(OsCode::KEY_F15)],
         Action::KeyCode(KeyCode::F15)
     );
-    assert_eq!(
-        layers[3][0][usize::from(OsCode::KEY_F13)],
-        Action::DefaultLayer(0)
-    );
-    assert_eq!(layers[3][0][usize::from(OsCode::KEY_F14)], Action::Layer(1));
-    assert_eq!(layers[3][0][usize::from(OsCode::KEY_F15)], Action::Trans);
 }
 
 #[test]
@@ -205,48 +319,221 @@ fn disallow_descendent_seq() {
     }
 }
 
+#[test]
+fn parse_chords() {
+    let RawCfg {
+        layers,
+        chord_groups,
+        ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/chords.kbd")).unwrap();
+    assert_eq!(chord_groups.len(), 2);
+    let jk = &chord_groups[0];
+    assert_eq!(jk.name, "jk");
+    assert_eq!(jk.timeout, 50);
+    assert!(jk.keys.contains(&u16::from(OsCode::KEY_J)));
+    assert!(!jk.keys.contains(&u16::from(OsCode::KEY_A)));
+    // The first chord output is placed after the single fake key. The larger j+k+l chord is
+    // checked first.
+    assert_eq!(jk.defs[0].1.len(), 3);
+    let (x, y) = jk.defs[1].0;
+    assert_eq!((x, y), (1, 1));
+    for layer in layers.iter().take(2) {
+        assert_eq!(layer[1][1], Action::KeyCode(KeyCode::Escape));
+    }
+    let press = |osc: OsCode| Event::Press(0, osc.into());
+    assert!(!jk.is_resolved(&[press(OsCode::KEY_J)]));
+    // j+k is contained in j+k+l so the timeout must be waited for.
+    assert!(!jk.is_resolved(&[press(OsCode::KEY_J), press(OsCode::KEY_K)]));
+    assert!(jk.is_resolved(&[
+        press(OsCode::KEY_J),
+        press(OsCode::KEY_K),
+        press(OsCode::KEY_L)
+    ]));
+    let sd = &chord_groups[1];
+    assert!(sd.is_resolved(&[press(OsCode::KEY_S), press(OsCode::KEY_D)]));
+}
+
+#[test]
+fn disallow_chord_key_in_multiple_groups() {
+    match parse_cfg(&std::path::PathBuf::from(
+        "./test_cfgs/chord_key_in_multiple_groups.kbd",
+    ))
+    .map_err(|e| e.to_string())
+    {
+        Ok(_) => panic!("chord key in multiple groups was Ok'd"),
+        Err(e) => assert!(e.contains("more than one defchords group"), "real e: {e}"),
+    }
+}
+
+#[test]
+fn parse_many_devices() {
+    let path = std::env::temp_dir().join(format!("kanata-devices-{}.kbd", std::process::id()));
+    let mut cfg = String::from("(defcfg) (defsrc a) (deflayer base b)");
+    for i in 0..10 {
+        cfg += &format!("(defdevice d{i} (id 1 {i}) (defsrc a) (deflayer base c))");
+    }
+    std::fs::write(&path, cfg).unwrap();
+    let RawCfg {
+        layers, devices, ..
+    } = parse_cfg_raw(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(devices.len(), 10);
+    assert_eq!(layers.rows_per_layer(), DEVICE_ROWS_START + 10);
+    assert_eq!(
+        layers[0][DEVICE_ROWS_START + 9][usize::from(OsCode::KEY_A)],
+        Action::KeyCode(KeyCode::C)
+    );
+}
+
+#[test]
+fn parse_devices() {
+    let RawCfg {
+        layers, devices, ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/devices.kbd")).unwrap();
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].name, "split");
+    assert_eq!(
+        devices[0].matchers,
+        vec![
+            DeviceMatcher::Name("ZSA Moonlander Mark I".into()),
+            DeviceMatcher::Id {
+                vendor: 0x3297,
+                product: 1969
+            },
+        ]
+    );
+    assert!(devices[0].mapped_keys.contains(&OsCode::KEY_S));
+    assert!(!devices[0].mapped_keys.contains(&OsCode::KEY_B));
+    assert_eq!(
+        devices[1].matchers,
+        vec![DeviceMatcher::Path(
+            "/dev/input/by-path/platform-i8042-serio-0-event-kbd".into()
+        )]
+    );
+
+    let a = usize::from(OsCode::KEY_A);
+    let s = usize::from(OsCode::KEY_S);
+    let split = DEVICE_ROWS_START;
+    let laptop = DEVICE_ROWS_START + 1;
+    let src = layers.len() - 1;
+    assert_eq!(layers.rows_per_layer(), DEVICE_ROWS_START + 2);
+    assert_eq!(layers[0][0][a], Action::Trans);
+    assert_eq!(layers[src][0][a], Action::KeyCode(KeyCode::A));
+    assert_eq!(layers[0][split][a], Action::KeyCode(KeyCode::X));
+    assert_eq!(layers[0][split][s], Action::Layer(1));
+    assert_eq!(layers[1][0][a], Action::KeyCode(KeyCode::C));
+    // The split device does not define the nav layer, so it behaves like a layer of `_`.
+    assert_eq!(layers[1][split][a], Action::Trans);
+    assert_eq!(layers[src][split][a], Action::KeyCode(KeyCode::A));
+    assert_eq!(layers[0][laptop][a], Action::KeyCode(KeyCode::Y));
+    assert_eq!(layers[1][laptop][a], Action::KeyCode(KeyCode::Z));
+}
+
+#[test]
+fn disallow_device_layer_without_top_level_layer() {
+    match parse_cfg(&std::path::PathBuf::from(
+        "./test_cfgs/device_layer_without_top_level_layer.kbd",
+    ))
+    .map_err(|e| e.to_string())
+    {
+        Ok(_) => panic!("device layer without top-level layer was Ok'd"),
+        Err(e) => assert!(e.contains("not defined at the top level"), "real e: {e}"),
+    }
+}
+
 #[derive(Debug)]
 pub struct LayerInfo {
     pub name: String,
     pub cfg_text: String,
 }
 
-#[allow(clippy::type_complexity)] // return type is not pub
-fn parse_cfg(
-    p: &std::path::Path,
-) -> Result<(
-    HashMap<String, String>,
-    MappedKeys,
-    Vec<LayerInfo>,
-    KeyOutputs,
-    KanataLayout,
-    KeySeqsToFKeys,
-)> {
-    let (cfg, src, layer_info, klayers, seqs) = parse_cfg_raw(p)?;
+/// The configuration text of the action of each alias, of the key list of each sequence and of
+/// the value of each variable, by name.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DefTexts {
+    pub aliases: BTreeMap<String, String>,
+    pub sequences: BTreeMap<String, String>,
+    pub variables: BTreeMap<String, String>,
+}
 
-    Ok((
-        cfg,
-        src,
+/// A configuration as returned by `parse_cfg_raw`, before its layers are turned into the key
+/// outputs and the layout. The fields are the same as those of `Cfg`.
+struct RawCfg {
+    items: HashMap<String, String>,
+    mapped_keys: MappedKeys,
+    layer_info: Vec<LayerInfo>,
+    layers: KanataLayers,
+    sequences: KeySeqsToFKeys,
+    chord_groups: ChordGroups,
+    devices: Vec<DeviceCfg>,
+    fake_keys: FakeKeys,
+    def_texts: DefTexts,
+    files: Vec<std::path::PathBuf>,
+    switch_configs: Vec<String>,
+}
+
+/// A configuration as returned by `parse_cfg`. It is a `Cfg` without the allocations that the
+/// layout and the chord groups reference.
+struct ParsedCfg {
+    items: HashMap<String, String>,
+    mapped_keys: MappedKeys,
+    layer_info: Vec<LayerInfo>,
+    key_outputs: KeyOutputs,
+    layout: KanataLayout,
+    sequences: KeySeqsToFKeys,
+    chord_groups: ChordGroups,
+    devices: Vec<DeviceCfg>,
+    fake_keys: FakeKeys,
+    def_texts: DefTexts,
+    files: Vec<std::path::PathBuf>,
+    switch_configs: Vec<String>,
+}
+
+fn parse_cfg(p: &std::path::Path) -> Result<ParsedCfg> {
+    let RawCfg {
+        items,
+        mapped_keys,
         layer_info,
-        create_key_outputs(&klayers),
-        create_layout(klayers),
-        seqs,
-    ))
+        layers,
+        sequences,
+        chord_groups,
+        mut devices,
+        fake_keys,
+        def_texts,
+        files,
+        switch_configs,
+    } = parse_cfg_raw(p)?;
+    for (i, device) in devices.iter_mut().enumerate() {
+        device.key_outputs = create_key_outputs(&layers, DEVICE_ROWS_START + i);
+    }
+
+    Ok(ParsedCfg {
+        items,
+        mapped_keys,
+        layer_info,
+        key_outputs: create_key_outputs(&layers, 0),
+        layout: create_layout(layers),
+        sequences,
+        chord_groups,
+        devices,
+        fake_keys,
+        def_texts,
+        files,
+        switch_configs,
+    })
 }
 
-#[allow(clippy::type_complexity)] // return type is not pub
-fn parse_cfg_raw(
-    p: &std::path::Path,
-) -> Result<(
-    HashMap<String, String>,
-    MappedKeys,
-    Vec<LayerInfo>,
-    Box<KanataLayers>,
-    KeySeqsToFKeys,
-)> {
-    let text = std::fs::read_to_string(p)?;
+fn parse_cfg_raw(p: &std::path::Path) -> Result<RawCfg> {
+    let mut files = vec![];
+    parse_cfg_raw_files(p, &mut files).map_err(|e| anyhow!(format_errors(&e, &files)))
+}
 
-    let spanned_root_exprs = sexpr::parse(&text)?;
+/// Parse the configuration file at `p` and the files it includes, which are added to `files`.
+/// Errors point at the part of the files that caused them.
+fn parse_cfg_raw_files(p: &std::path::Path, files: &mut Vec<SourceFile>) -> Result<RawCfg> {
+    let mut spanned_root_exprs = parse_file_with_includes(p, None, files, &mut vec![])?;
+    expand_templates(&mut spanned_root_exprs)?;
+    substitute_vars(&mut spanned_root_exprs)?;
     // TODO: get rid of clone
     let root_exprs: Vec<_> = spanned_root_exprs.iter().map(|t| t.t.clone()).collect();
 
@@ -278,7 +565,11 @@ fn parse_cfg_raw(
     }
     let (src, mapping_order) = parse_defsrc(src_expr, &cfg)?;
 
-    let deflayer_filter = gen_first_atom_filter("deflayer");
+    let deflayer_filter = {
+        let deflayer = gen_first_atom_filter("deflayer");
+        let deflayermap = gen_first_atom_filter("deflayermap");
+        move |expr: &&Vec<SExpr>| deflayer(expr) || deflayermap(expr)
+    };
     let layer_exprs = root_exprs
         .iter()
         .filter(&deflayer_filter)
@@ -287,11 +578,11 @@ fn parse_cfg_raw(
     if layer_exprs.is_empty() {
         bail!("No deflayer expressions exist. At least one layer must be defined.")
     }
-    if layer_exprs.len() > MAX_LAYERS {
-        bail!("Exceeded the maximum layer count of {}", MAX_LAYERS)
-    }
 
-    let layer_idxs = parse_layer_indexes(&layer_exprs, mapping_order.len())?;
+    // Independent errors are collected so that they can be reported together.
+    let mut errors = vec![];
+
+    let layer_idxs = parse_layer_indexes(&layer_exprs, mapping_order.len(), &mut errors)?;
     let mut sorted_idxs: Vec<(&String, &usize)> =
         layer_idxs.iter().map(|tuple| (tuple.0, tuple.1)).collect();
 
@@ -303,22 +594,12 @@ fn parse_cfg_raw(
     let layer_names = sorted_idxs
         .into_iter()
         .map(|(name, _)| (*name).clone())
-        .flat_map(|s| {
-            // Duplicate the same layer for `layer_strings` because the keyberon layout itself has
-            // two versions of each layer.
-            std::iter::repeat(s).take(2)
-        })
         .collect::<Vec<_>>();
 
     let layer_strings = spanned_root_exprs
         .iter()
         .filter(|expr| deflayer_filter(&&expr.t))
-        .map(|expr| text[expr.span].to_string())
-        .flat_map(|s| {
-            // Duplicate the same layer for `layer_strings` because the keyberon layout itself has
-            // two versions of each layer.
-            std::iter::repeat(s).take(2)
-        })
+        .map(|expr| files[expr.span.file_id()].text[expr.span].to_string())
         .collect::<Vec<_>>();
 
     let layer_info: Vec<LayerInfo> = layer_names
@@ -340,7 +621,7 @@ fn parse_cfg_raw(
         is_cmd_enabled: {
             #[cfg(feature = "cmd")]
             {
-                cfg.get("danger-enable-cmd").map_or(false, |s| {
+                cfg.get("danger-enable-cmd").is_some_and(|s| {
                     if s == "yes" {
                         log::warn!("DANGER! cmd action is enabled.");
                         true
@@ -362,19 +643,533 @@ fn parse_cfg_raw(
         .iter()
         .filter(gen_first_atom_filter("deffakekeys"))
         .collect::<Vec<_>>();
-    parse_fake_keys(&fake_keys_exprs, &mut parsed_state)?;
+    parse_fake_keys(&fake_keys_exprs, &mut parsed_state).map_err(|e| add_error(&mut errors, e))?;
+
+    let hands_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defhands"))
+        .collect::<Vec<_>>();
+    parsed_state.hands = parse_hands(&hands_exprs, &src).map_err(|e| add_error(&mut errors, e))?;
 
     let sequence_exprs = root_exprs
         .iter()
         .filter(gen_first_atom_filter("defseq"))
         .collect::<Vec<_>>();
-    let sequences = parse_sequences(&sequence_exprs, &parsed_state)?;
+    let sequences =
+        parse_sequences(&sequence_exprs, &parsed_state).map_err(|e| add_error(&mut errors, e))?;
+
+    if let Err(e) = parse_aliases(&alias_exprs, &mut parsed_state) {
+        errors.push(e);
+    }
+
+    let chord_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defchords"))
+        .collect::<Vec<_>>();
+    let chord_groups = parse_chord_groups(&chord_exprs, &src, &mut parsed_state)
+        .map_err(|e| add_error(&mut errors, e))?;
+
+    let device_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defdevice"))
+        .collect::<Vec<_>>();
+    let devices = parse_defdevices(&device_exprs, &cfg, &mut parsed_state)
+        .map_err(|e| add_error(&mut errors, e))?;
+
+    let klayers = parse_layers(&parsed_state).map_err(|e| add_error(&mut errors, e))?;
+    combine(errors)?;
+
+    let fake_keys = parsed_state
+        .fake_keys
+        .iter()
+        .map(|(name, (y, _))| (name.clone(), get_fake_key_coords(*y)))
+        .collect();
+
+    Ok(RawCfg {
+        items: cfg,
+        mapped_keys: src,
+        layer_info,
+        layers: klayers,
+        sequences,
+        chord_groups,
+        devices,
+        fake_keys,
+        def_texts: parse_def_texts(&root_exprs, files),
+        files: files.iter().map(|f| f.path.clone()).collect(),
+        switch_configs: parse_switch_config_names(&root_exprs),
+    })
+}
+
+/// Returns the names of the configurations that the `switch-config` actions in `root_exprs`
+/// switch to.
+fn parse_switch_config_names(root_exprs: &[Vec<SExpr>]) -> Vec<String> {
+    fn collect(exprs: &[SExpr], names: &mut Vec<String>) {
+        match exprs {
+            [SExpr::Atom(action), SExpr::Atom(name)] if action.t == "switch-config" => {
+                if !names.contains(&name.t) {
+                    names.push(name.t.clone());
+                }
+            }
+            _ => {
+                for expr in exprs {
+                    if let SExpr::List(list) = expr {
+                        collect(&list.t, names);
+                    }
+                }
+            }
+        }
+    }
+    let mut names = vec![];
+    for expr in root_exprs {
+        collect(expr, &mut names);
+    }
+    names
+}
+
+/// Returns the text of the aliases, sequences and variables in `root_exprs`, which point into `files`.
+fn parse_def_texts(root_exprs: &[Vec<SExpr>], files: &[SourceFile]) -> DefTexts {
+    let pairs = |def: &str| {
+        root_exprs
+            .iter()
+            .filter(gen_first_atom_filter(def))
+            .flat_map(|expr| expr[1..].chunks_exact(2))
+            .filter_map(|pair| {
+                let span = pair[1].span();
+                Some((
+                    pair[0].atom()?.to_owned(),
+                    files[span.file_id()].text[span].to_owned(),
+                ))
+            })
+            .collect()
+    };
+    DefTexts {
+        aliases: pairs("defalias"),
+        sequences: pairs("defseq"),
+        variables: pairs("defvar"),
+    }
+}
+
+/// Parse the file at `path`, replacing its `(include <path>)` expressions with the expressions of
+/// the file they name, which is resolved relative to `path`. `include_span` is the span of the
+/// expression that included `path`, if any, and `including` holds the canonical paths of the files
+/// currently being included, to detect cycles. Conditional sections are evaluated as well, see
+/// `expand_root_exprs`.
+fn parse_file_with_includes(
+    path: &std::path::Path,
+    include_span: Option<sexpr::Span>,
+    files: &mut Vec<SourceFile>,
+    including: &mut Vec<std::path::PathBuf>,
+) -> Result<Vec<Spanned<Vec<SExpr>>>> {
+    let read_err = |e: std::io::Error| {
+        let e = anyhow!("could not read {}: {e}", path.to_string_lossy());
+        match include_span {
+            Some(span) => spanned(e, span),
+            None => e,
+        }
+    };
+    let canonical_path = path.canonicalize().map_err(read_err)?;
+    if let Some(i) = including.iter().position(|p| *p == canonical_path) {
+        let cycle = including[i..]
+            .iter()
+            .chain(std::iter::once(&canonical_path))
+            .map(|p| p.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        let e = anyhow!("include cycle: {cycle}");
+        return Err(match include_span {
+            Some(span) => spanned(e, span),
+            None => e,
+        });
+    }
+    let text = std::fs::read_to_string(path).map_err(read_err)?;
+    let file_id = files.len();
+    files.push(SourceFile {
+        path: path.to_owned(),
+        text,
+    });
+    let exprs = sexpr::parse(&files[file_id].text, file_id)?;
+
+    including.push(canonical_path);
+    let all_exprs = expand_root_exprs(exprs, path, files, including)?;
+    including.pop();
+    Ok(all_exprs)
+}
+
+/// Platforms that `(platform (<platforms>) ...)` accepts.
+const PLATFORMS: &[&str] = &["linux", "windows", "macos"];
+
+/// Replace the includes and conditional sections among the root expressions `exprs` of the file
+/// at `path` with the expressions they stand for. A `(platform (<platforms>) ...)` section is
+/// used on the given platforms and an `(environment (<variable> <value>) ...)` section when the
+/// environment variable has the given value, where `""` also matches an unset variable.
+fn expand_root_exprs(
+    exprs: Vec<Spanned<Vec<SExpr>>>,
+    path: &std::path::Path,
+    files: &mut Vec<SourceFile>,
+    including: &mut Vec<std::path::PathBuf>,
+) -> Result<Vec<Spanned<Vec<SExpr>>>> {
+    let mut all_exprs = vec![];
+    for expr in exprs {
+        let is_conditional = gen_first_atom_filter("platform")(&&expr.t)
+            || gen_first_atom_filter("environment")(&&expr.t);
+        if is_conditional {
+            if !condition_is_met(&expr)? {
+                continue;
+            }
+            let section = expr
+                .t
+                .into_iter()
+                .skip(2)
+                .map(|e| match e {
+                    SExpr::List(l) => Ok(l),
+                    SExpr::Atom(a) => bail_span!(a.span, "Top level must be lists"),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            all_exprs.extend(expand_root_exprs(section, path, files, including)?);
+            continue;
+        }
+        if !gen_first_atom_filter("include")(&&expr.t) {
+            all_exprs.push(expr);
+            continue;
+        }
+        let included_path = match expr.t.as_slice() {
+            [_, SExpr::Atom(p)] => unquote(&p.t),
+            _ => bail_span!(expr.span, "include expects a single file path"),
+        };
+        let included_path = path
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""))
+            .join(included_path);
+        all_exprs.extend(parse_file_with_includes(
+            &included_path,
+            Some(expr.span),
+            files,
+            including,
+        )?);
+    }
+    Ok(all_exprs)
+}
+
+/// Returns whether the condition of a `platform` or `environment` section is met.
+fn condition_is_met(expr: &Spanned<Vec<SExpr>>) -> Result<bool> {
+    let (kind, condition) = match expr.t.as_slice() {
+        [SExpr::Atom(kind), SExpr::List(condition), ..] => (kind.t.as_str(), condition),
+        [SExpr::Atom(kind), ..] => bail_span!(
+            expr.span,
+            "{} expects a condition followed by configuration entries",
+            kind.t
+        ),
+        _ => unreachable!("conditional sections start with an atom"),
+    };
+    if kind == "platform" {
+        let mut is_met = false;
+        for platform in condition.t.iter() {
+            match platform {
+                SExpr::Atom(p) if PLATFORMS.contains(&p.t.as_str()) => {
+                    is_met |= p.t == std::env::consts::OS;
+                }
+                _ => bail_span!(
+                    platform.span(),
+                    "Unknown platform, valid platforms are: {}",
+                    PLATFORMS.join(", ")
+                ),
+            }
+        }
+        return Ok(is_met);
+    }
+    match condition.t.as_slice() {
+        [SExpr::Atom(var), SExpr::Atom(value)] => Ok(env_var(&var.t) == unquote(&value.t)),
+        _ => bail_span!(
+            condition.span,
+            "environment expects a condition of a variable name and a value, e.g. (HOSTNAME \"box1\")"
+        ),
+    }
+}
+
+/// Returns the value of the environment variable `name`, or `""` if it is unset. An unset
+/// `HOSTNAME` falls back to the name of the machine, since shells often don't export it.
+fn env_var(name: &str) -> String {
+    if let Ok(value) = std::env::var(name) {
+        return value;
+    }
+    if name == "HOSTNAME" {
+        #[cfg(target_os = "linux")]
+        {
+            let mut buf = [0u8; 256];
+            if let Ok(host) = nix::unistd::gethostname(&mut buf) {
+                return host.to_string_lossy().into_owned();
+            }
+        }
+        #[cfg(target_os = "windows")]
+        if let Ok(host) = std::env::var("COMPUTERNAME") {
+            return host;
+        }
+    }
+    String::new()
+}
+
+/// A template defined with `(deftemplate name (params ...) body ...)`.
+struct Template {
+    params: Vec<String>,
+    body: Vec<SExpr>,
+}
+
+/// Remove the `deftemplate` expressions from `root_exprs` and replace every
+/// `(t! name args ...)` after them with the body of the template `name`, in which each `$param`
+/// is replaced by the corresponding argument. The expanded body points at the `t!` expression
+/// that produced it, except for the arguments, which keep pointing at where they were written.
+fn expand_templates(root_exprs: &mut Vec<Spanned<Vec<SExpr>>>) -> Result<()> {
+    let mut templates = HashMap::default();
+    let mut errors = vec![];
+    for mut expr in std::mem::take(root_exprs) {
+        if gen_first_atom_filter("deftemplate")(&&expr.t) {
+            match parse_template(&expr, &templates) {
+                Ok((name, template)) => {
+                    templates.insert(name, template);
+                }
+                Err(e) => errors.push(e),
+            }
+            continue;
+        }
+        if is_template_call(&expr.t) {
+            let call = Spanned::new(std::mem::take(&mut expr.t), expr.span);
+            match expand_template_call(&call, &templates, &mut errors) {
+                Ok(exprs) => root_exprs.extend(exprs.into_iter().filter_map(|e| match e {
+                    SExpr::List(l) => Some(l),
+                    SExpr::Atom(a) => {
+                        errors.push(spanned(anyhow!("Top level must be lists"), a.span));
+                        None
+                    }
+                })),
+                Err(e) => errors.push(e),
+            }
+            continue;
+        }
+        expand_templates_in_list(&mut expr.t, &templates, &mut errors);
+        root_exprs.push(expr);
+    }
+    combine(errors)
+}
+
+fn parse_template(
+    expr: &Spanned<Vec<SExpr>>,
+    templates: &HashMap<String, Template>,
+) -> Result<(String, Template)> {
+    const ERR_MSG: &str = "deftemplate expects a name, a list of parameters and a body";
+    let (name, params, body) = match expr.t.as_slice() {
+        [_, SExpr::Atom(name), SExpr::List(params), body @ ..] if !body.is_empty() => {
+            (name, params, body)
+        }
+        _ => bail_span!(expr.span, "{ERR_MSG}"),
+    };
+    if templates.contains_key(&name.t) {
+        bail_span!(name.span, "template {} is defined more than once", name.t);
+    }
+    let params = params
+        .t
+        .iter()
+        .map(|param| match param {
+            SExpr::Atom(p) if !p.t.starts_with('$') => Ok(p.t.clone()),
+            _ => bail_span!(
+                param.span(),
+                "template parameters must be atoms without a leading $"
+            ),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Only templates defined before this one may be used in its body, so expansion always ends.
+    check_template_calls(body, templates)?;
+    Ok((
+        name.t.clone(),
+        Template {
+            params,
+            body: body.to_vec(),
+        },
+    ))
+}
+
+fn check_template_calls(exprs: &[SExpr], templates: &HashMap<String, Template>) -> Result<()> {
+    for expr in exprs {
+        if let SExpr::List(l) = expr {
+            if is_template_call(&l.t) {
+                if let Some(SExpr::Atom(name)) = l.t.get(1) {
+                    if !templates.contains_key(&name.t) {
+                        bail_span!(
+                            name.span,
+                            "unknown template {}, templates can only use templates defined before them",
+                            name.t
+                        );
+                    }
+                }
+            }
+            check_template_calls(&l.t, templates)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_template_call(list: &[SExpr]) -> bool {
+    matches!(list.first(), Some(SExpr::Atom(a)) if a.t == "t!")
+}
+
+/// Expand the template calls in `list`, splicing the body of each template into it.
+fn expand_templates_in_list(
+    list: &mut Vec<SExpr>,
+    templates: &HashMap<String, Template>,
+    errors: &mut Vec<anyhow::Error>,
+) {
+    let mut i = 0;
+    while i < list.len() {
+        if let SExpr::List(l) = &mut list[i] {
+            if is_template_call(&l.t) {
+                match expand_template_call(l, templates, errors) {
+                    Ok(exprs) => {
+                        let len = exprs.len();
+                        list.splice(i..=i, exprs);
+                        i += len;
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        i += 1;
+                    }
+                }
+                continue;
+            }
+            expand_templates_in_list(&mut l.t, templates, errors);
+        }
+        i += 1;
+    }
+}
+
+/// Returns the expanded body of the template called by `call`, i.e. `(t! name args ...)`.
+fn expand_template_call(
+    call: &Spanned<Vec<SExpr>>,
+    templates: &HashMap<String, Template>,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<Vec<SExpr>> {
+    let (name, args) = match call.t.as_slice() {
+        [_, SExpr::Atom(name), args @ ..] => (name, args),
+        _ => bail_span!(
+            call.span,
+            "t! expects the name of a template followed by its arguments"
+        ),
+    };
+    let template = match templates.get(&name.t) {
+        Some(template) => template,
+        None => bail_span!(name.span, "unknown template {}", name.t),
+    };
+    if args.len() != template.params.len() {
+        bail_span!(
+            call.span,
+            "template {} expects {} arguments ({}), found {}",
+            name.t,
+            template.params.len(),
+            template.params.join(" "),
+            args.len()
+        );
+    }
+    let mut body = template.body.clone();
+    for expr in body.iter_mut() {
+        set_span(expr, call.span);
+        substitute_params(expr, &template.params, args);
+    }
+    expand_templates_in_list(&mut body, templates, errors);
+    Ok(body)
+}
+
+fn set_span(expr: &mut SExpr, span: sexpr::Span) {
+    match expr {
+        SExpr::Atom(a) => a.span = span,
+        SExpr::List(l) => {
+            l.span = span;
+            for e in l.t.iter_mut() {
+                set_span(e, span);
+            }
+        }
+    }
+}
 
-    parse_aliases(&alias_exprs, &mut parsed_state)?;
+fn substitute_params(expr: &mut SExpr, params: &[String], args: &[SExpr]) {
+    match expr {
+        SExpr::Atom(a) => {
+            let param = a.t.strip_prefix('$');
+            if let Some(i) = params.iter().position(|p| Some(p.as_str()) == param) {
+                *expr = args[i].clone();
+            }
+        }
+        SExpr::List(l) => {
+            for e in l.t.iter_mut() {
+                substitute_params(e, params, args);
+            }
+        }
+    }
+}
 
-    let klayers = parse_layers(&parsed_state)?;
+/// Replace every `$name` atom outside of `defvar` with the value of the variable `name`, which is
+/// defined with `(defvar name value ...)`. The value is an atom or a list and may use the
+/// variables defined before it.
+fn substitute_vars(root_exprs: &mut [Spanned<Vec<SExpr>>]) -> Result<()> {
+    let mut vars = HashMap::default();
+    let mut errors = vec![];
+    let is_defvar = gen_first_atom_filter("defvar");
+    for expr in root_exprs.iter_mut().filter(|expr| is_defvar(&&expr.t)) {
+        if expr.t.len() % 2 == 0 {
+            errors.push(spanned(
+                anyhow!("defvar expects pairs of a variable name and a value"),
+                expr.span,
+            ));
+            continue;
+        }
+        for pair in expr.t[1..].chunks_exact_mut(2) {
+            let [name, value] = pair else { unreachable!() };
+            let name = match name {
+                SExpr::Atom(name) if !name.t.starts_with('$') => name,
+                other => {
+                    errors.push(spanned(
+                        anyhow!("variable names must be atoms without a leading $"),
+                        other.span(),
+                    ));
+                    continue;
+                }
+            };
+            if vars.contains_key(&name.t) {
+                errors.push(spanned(
+                    anyhow!("variable {} is defined more than once", name.t),
+                    name.span,
+                ));
+                continue;
+            }
+            substitute_vars_in_expr(value, &vars);
+            vars.insert(name.t.clone(), value.clone());
+        }
+    }
+    for expr in root_exprs.iter_mut().filter(|expr| !is_defvar(&&expr.t)) {
+        for e in expr.t.iter_mut() {
+            substitute_vars_in_expr(e, &vars);
+        }
+    }
+    combine(errors)
+}
 
-    Ok((cfg, src, layer_info, klayers, sequences))
+/// Replace atoms of a `$` followed by the name of a variable with the variable's value. Other
+/// atoms starting with `$`, like `$HOME` in a `cmd` argument, are left as they are.
+fn substitute_vars_in_expr(expr: &mut SExpr, vars: &HashMap<String, SExpr>) {
+    let value = match expr {
+        SExpr::Atom(a) => match a.t.strip_prefix('$').and_then(|name| vars.get(name)) {
+            Some(value) => value,
+            None => return,
+        },
+        SExpr::List(l) => {
+            for e in l.t.iter_mut() {
+                substitute_vars_in_expr(e, vars);
+            }
+            return;
+        }
+    };
+    // Atoms keep pointing at where the variable is used, so that errors about the value do too.
+    *expr = match (value, &*expr) {
+        (SExpr::Atom(value), SExpr::Atom(a)) => SExpr::Atom(Spanned::new(value.t.clone(), a.span)),
+        (value, _) => value.clone(),
+    };
 }
 
 /// Return a closure that filters a root expression by the content of the first element. The
@@ -427,7 +1222,7 @@ fn parse_defcfg(expr: &[SExpr]) -> Result<HashMap<String, String>> {
         };
         match (&key, &val) {
             (SExpr::Atom(k), SExpr::Atom(v)) => {
-                if cfg.insert(k.t.clone(), v.t.clone()).is_some() {
+                if cfg.insert(k.t.clone(), unquote(&v.t)).is_some() {
                     bail!("duplicate cfg entries for key {}", k.t);
                 }
             }
@@ -452,18 +1247,33 @@ fn parse_defsrc(
     let exprs = check_first_expr(expr.iter(), "defsrc")?;
     let mut mkeys = MappedKeys::default();
     let mut ordered_codes = Vec::new();
+    let mut errors = vec![];
     for expr in exprs {
-        let s = match expr {
-            SExpr::Atom(a) => &a.t,
-            _ => bail!("No lists allowed in defsrc"),
-        };
-        let oscode = str_to_oscode(s).ok_or_else(|| anyhow!("Unknown key in defsrc: \"{}\"", s))?;
-        if mkeys.contains(&oscode) {
-            bail!("Repeat declaration of key in defsrc: \"{}\"", s)
+        let res: Result<()> = (|| {
+            let s = match expr {
+                SExpr::Atom(a) => &a.t,
+                _ => bail_span!(expr.span(), "No lists allowed in defsrc"),
+            };
+            let oscode = match str_to_oscode(s) {
+                Some(oscode) => oscode,
+                None => bail_span!(expr.span(), "Unknown key in defsrc: \"{}\"", s),
+            };
+            if mkeys.contains(&oscode) {
+                bail_span!(
+                    expr.span(),
+                    "Repeat declaration of key in defsrc: \"{}\"",
+                    s
+                )
+            }
+            mkeys.insert(oscode);
+            ordered_codes.push(oscode.into());
+            Ok(())
+        })();
+        if let Err(e) = res {
+            errors.push(e);
         }
-        mkeys.insert(oscode);
-        ordered_codes.push(oscode.into());
     }
+    combine(errors)?;
 
     let process_unmapped_keys = defcfg
         .get("process-unmapped-keys")
@@ -491,25 +1301,57 @@ type LayerIndexes = HashMap<String, usize>;
 type Aliases = HashMap<String, &'static KanataAction>;
 
 /// Returns layer names and their indexes into the keyberon layout. This also checks that all
-/// layers have the same number of items as the defsrc.
-fn parse_layer_indexes(exprs: &[&Vec<SExpr>], expected_len: usize) -> Result<LayerIndexes> {
+/// `deflayer` layers have the same number of items as the defsrc and that all `deflayermap` layers
+/// have pairs of items, adding an error to `errors` for each layer that does not.
+fn parse_layer_indexes(
+    exprs: &[&Vec<SExpr>],
+    expected_len: usize,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<LayerIndexes> {
     let mut layer_indexes = HashMap::default();
     for (i, expr) in exprs.iter().enumerate() {
-        let mut subexprs = check_first_expr(expr.iter(), "deflayer")?;
-        let layer_name = subexprs
+        let deflayer = expr[0].atom().unwrap_or_default();
+        let mut subexprs = expr.iter().skip(1);
+        let name_expr = subexprs
             .next()
-            .ok_or_else(|| anyhow!("deflayer requires a name and keys"))?
-            .atom()
-            .ok_or_else(|| anyhow!("layer name after deflayer must be an atom"))?
-            .to_owned();
+            .ok_or_else(|| anyhow!("{deflayer} requires a name and keys"))?;
+        let layer_name = match name_expr {
+            SExpr::Atom(name) => name.t.clone(),
+            SExpr::List(list) => match list.t.as_slice() {
+                [SExpr::Atom(name), SExpr::Atom(inherits), SExpr::Atom(_)]
+                    if inherits.t == "inherits" =>
+                {
+                    name.t.clone()
+                }
+                _ => bail_span!(
+                    name_expr.span(),
+                    "layer name after {deflayer} must be an atom or (<name> inherits <parent layer>)"
+                ),
+            },
+        };
         let num_actions = subexprs.count();
-        if num_actions != expected_len {
-            bail!(
-                "layer {} has {} items, but requires {} to match defsrc",
-                layer_name,
-                num_actions,
-                expected_len
-            )
+        if deflayer == "deflayermap" {
+            if num_actions % 2 != 0 {
+                errors.push(
+                    SpannedError {
+                        msg: format!(
+                            "layer {layer_name} has {num_actions} items, but deflayermap requires pairs of a key and an action"
+                        ),
+                        span: sexpr::exprs_span(expr),
+                    }
+                    .into(),
+                );
+            }
+        } else if num_actions != expected_len {
+            errors.push(
+                SpannedError {
+                    msg: format!(
+                        "layer {layer_name} has {num_actions} items, but requires {expected_len} to match defsrc"
+                    ),
+                    span: sexpr::exprs_span(expr),
+                }
+                .into(),
+            );
         }
         layer_indexes.insert(layer_name, i);
     }
@@ -524,10 +1366,23 @@ struct ParsedState<'a> {
     layer_idxs: LayerIndexes,
     mapping_order: Vec<usize>,
     fake_keys: HashMap<String, (usize, &'static KanataAction)>,
+    chord_actions: Vec<(usize, &'static KanataAction)>,
     defsrc_layer: [KanataAction; KEYS_IN_ROW],
+    devices: Vec<ParsedDevice<'a>>,
+    /// The hand of the keys in `defhands`, by `OsCode`.
+    hands: Option<&'static HashMap<u16, Hand>>,
     is_cmd_enabled: bool,
 }
 
+/// The parts of a `defdevice` block needed to fill in its row of the keyberon layers.
+#[derive(Debug)]
+struct ParsedDevice<'a> {
+    mapping_order: Vec<usize>,
+    defsrc_layer: [KanataAction; KEYS_IN_ROW],
+    /// The `deflayer` expressions of the block by layer index.
+    layer_exprs: HashMap<usize, &'a [SExpr]>,
+}
+
 impl<'a> Default for ParsedState<'a> {
     fn default() -> Self {
         Self {
@@ -537,6 +1392,9 @@ impl<'a> Default for ParsedState<'a> {
             mapping_order: Default::default(),
             defsrc_layer: [KanataAction::Trans; KEYS_IN_ROW],
             fake_keys: Default::default(),
+            chord_actions: Default::default(),
+            devices: Default::default(),
+            hands: None,
             is_cmd_enabled: false,
         }
     }
@@ -545,6 +1403,7 @@ impl<'a> Default for ParsedState<'a> {
 /// Parse alias->action mappings from multiple exprs starting with defalias.
 /// Mutates the input `parsed_state` by storing aliases inside.
 fn parse_aliases(exprs: &[&Vec<SExpr>], parsed_state: &mut ParsedState) -> Result<()> {
+    let mut errors = vec![];
     for expr in exprs {
         let mut subexprs = check_first_expr(expr.iter(), "defalias")?;
         // Read k-v pairs from the configuration
@@ -553,27 +1412,49 @@ fn parse_aliases(exprs: &[&Vec<SExpr>], parsed_state: &mut ParsedState) -> Resul
                 Some(v) => v,
                 None => bail!("Incorrect number of elements found in defalias; they should be pairs of aliases and actions."),
             };
-            let alias = match alias {
+            let alias_name = match alias {
                 SExpr::Atom(a) => &a.t,
-                _ => bail!("Alias keys must be atoms. Invalid alias: {:?}", alias),
+                _ => bail_span!(
+                    alias.span(),
+                    "Alias keys must be atoms. Invalid alias: {:?}",
+                    alias
+                ),
             };
-            let action = parse_action(action, parsed_state)?;
-            if parsed_state.aliases.insert(alias.into(), action).is_some() {
-                bail!("Duplicate alias: {}", alias);
+            let action = match parse_action(action, parsed_state) {
+                Ok(action) => action,
+                Err(e) => {
+                    errors.push(e);
+                    // Keep going so that errors in other aliases and in the layers are found,
+                    // without also reporting every use of this alias as unknown.
+                    unsafe { sref(Action::NoOp) }
+                }
+            };
+            if parsed_state
+                .aliases
+                .insert(alias_name.into(), action)
+                .is_some()
+            {
+                errors.push(
+                    SpannedError {
+                        msg: format!("Duplicate alias: {alias_name}"),
+                        span: alias.span(),
+                    }
+                    .into(),
+                );
             }
         }
     }
-    Ok(())
-}
-
-/// Returns a `&'static T` by leaking a box.
-fn sref<T>(v: T) -> &'static T {
-    Box::leak(Box::new(v))
+    combine(errors)
 }
 
-/// Returns a `&'static [&'static T]` by leaking a box + boxed array
-fn sref_slice<T>(v: T) -> &'static [&'static T] {
-    Box::leak(vec![sref(v)].into_boxed_slice())
+/// Returns a `&'static [&'static T]` that is freed along with the other allocations of the
+/// configuration.
+///
+/// # Safety
+///
+/// The same as for `sref`.
+unsafe fn sref_slice<T: Send + Sync + 'static>(v: T) -> &'static [&'static T] {
+    unsafe { sref(vec![sref(v)]) }.as_slice()
 }
 
 /// Parse a `kanata_keyberon::action::Action` from a `SExpr`.
@@ -582,75 +1463,82 @@ fn parse_action(expr: &SExpr, parsed_state: &ParsedState) -> Result<&'static Kan
         SExpr::Atom(a) => parse_action_atom(a, &parsed_state.aliases),
         SExpr::List(l) => parse_action_list(&l.t, parsed_state),
     }
+    .map_err(|e| spanned(e, expr.span()))
 }
 
 /// Parse a `kanata_keyberon::action::Action` from a string.
 fn parse_action_atom(ac: &Spanned<String>, aliases: &Aliases) -> Result<&'static KanataAction> {
     let ac = &*ac.t;
     match ac {
-        "_" => return Ok(sref(Action::Trans)),
-        "XX" => return Ok(sref(Action::NoOp)),
-        "lrld" => return Ok(sref(Action::Custom(sref_slice(CustomAction::LiveReload)))),
+        "_" => return Ok(unsafe { sref(Action::Trans) }),
+        "XX" => return Ok(unsafe { sref(Action::NoOp) }),
+        "lrld" => return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::LiveReload))) }),
         "sldr" => {
-            return Ok(sref(Action::Custom(sref_slice(
-                CustomAction::SequenceLeader,
-            ))))
+            return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::SequenceLeader))) })
         }
         "mlft" | "mouseleft" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::Mouse(
-                Btn::Left,
-            )))))
+            return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::Mouse(Btn::Left)))) })
         }
         "mrgt" | "mouseright" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::Mouse(
-                Btn::Right,
-            )))))
+            return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::Mouse(Btn::Right)))) })
         }
         "mmid" | "mousemid" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::Mouse(
-                Btn::Mid,
-            )))))
+            return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::Mouse(Btn::Mid)))) })
         }
         "mfwd" | "mouseforward" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::Mouse(
-                Btn::Forward,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::Mouse(
+                    Btn::Forward,
+                ))))
+            })
         }
         "mbck" | "mousebackward" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::Mouse(
-                Btn::Backward,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::Mouse(
+                    Btn::Backward,
+                ))))
+            })
         }
         "mltp" | "mousetapleft" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::MouseTap(
-                Btn::Left,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::MouseTap(
+                    Btn::Left,
+                ))))
+            })
         }
         "mrtp" | "mousetapright" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::MouseTap(
-                Btn::Right,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::MouseTap(
+                    Btn::Right,
+                ))))
+            })
         }
         "mmtp" | "mousetapmid" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::MouseTap(
-                Btn::Mid,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::MouseTap(Btn::Mid))))
+            })
         }
         "mftp" | "mousetapforward" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::MouseTap(
-                Btn::Forward,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::MouseTap(
+                    Btn::Forward,
+                ))))
+            })
         }
         "mbtp" | "mousetapbackward" => {
-            return Ok(sref(Action::Custom(sref_slice(CustomAction::MouseTap(
-                Btn::Backward,
-            )))))
+            return Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::MouseTap(
+                    Btn::Backward,
+                ))))
+            })
+        }
+        "rpt" | "repeat" => {
+            return Ok(unsafe { sref(Action::Custom(sref_slice(CustomAction::Repeat))) })
         }
-        "rpt" | "repeat" => return Ok(sref(Action::Custom(sref_slice(CustomAction::Repeat)))),
         _ => {}
     };
     if let Some(oscode) = str_to_oscode(ac) {
-        return Ok(sref(k(oscode.into())));
+        return Ok(unsafe { sref(k(oscode.into())) });
     }
     if let Some(alias) = ac.strip_prefix('@') {
         return match aliases.get(alias) {
@@ -669,13 +1557,13 @@ fn parse_action_atom(ac: &Spanned<String>, aliases: &Aliases) -> Result<&'static
             .ok_or_else(|| anyhow!("Could not parse: {ac:?}"))?
             .into(),
     );
-    Ok(sref(Action::MultipleKeyCodes(sref(keys).as_ref())))
+    Ok(unsafe { sref(Action::MultipleKeyCodes(sref(keys).as_ref())) })
 }
 
 /// Parse a `kanata_keyberon::action::Action` from a `SExpr::List`.
 fn parse_action_list(ac: &[SExpr], parsed_state: &ParsedState) -> Result<&'static KanataAction> {
     if ac.is_empty() {
-        return Ok(sref(Action::NoOp));
+        return Ok(unsafe { sref(Action::NoOp) });
     }
     let ac_type = match &ac[0] {
         SExpr::Atom(a) => &a.t,
@@ -688,6 +1576,9 @@ fn parse_action_list(ac: &[SExpr], parsed_state: &ParsedState) -> Result<&'stati
         "tap-hold" => parse_tap_hold(&ac[1..], parsed_state, HoldTapConfig::Default),
         "tap-hold-press" => parse_tap_hold(&ac[1..], parsed_state, HoldTapConfig::HoldOnOtherKeyPress),
         "tap-hold-release" => parse_tap_hold(&ac[1..], parsed_state, HoldTapConfig::PermissiveHold),
+        "tap-hold-release-keys" => parse_tap_hold_keys(&ac[1..], parsed_state, "tap-hold-release-keys", tap_on_keys_else_permissive_hold),
+        "tap-hold-press-keys" => parse_tap_hold_keys(&ac[1..], parsed_state, "tap-hold-press-keys", hold_on_keys_else_tap),
+        "tap-hold-opposite-hand" => parse_tap_hold_opposite_hand(&ac[1..], parsed_state),
         "multi" => parse_multi(&ac[1..], parsed_state),
         "macro" => parse_macro(&ac[1..], parsed_state),
         "macro-release-cancel" => parse_macro_release_cancel(&ac[1..], parsed_state),
@@ -705,21 +1596,24 @@ fn parse_action_list(ac: &[SExpr], parsed_state: &ParsedState) -> Result<&'stati
         "mwheel-left" => parse_mwheel(&ac[1..], MWheelDirection::Left),
         "mwheel-right" => parse_mwheel(&ac[1..], MWheelDirection::Right),
         "cmd" => parse_cmd(&ac[1..], parsed_state.is_cmd_enabled),
+        "switch-config" => parse_switch_config(&ac[1..]),
+        "caps-word" => parse_caps_word(&ac[1..], false),
+        "caps-word-underscore" => parse_caps_word(&ac[1..], true),
         _ => bail!(
-            "Unknown action type: {}. Valid types:\n\tlayer-switch\n\tlayer-toggle | layer-while-held\n\ttap-hold | tap-hold-press | tap-hold-release\n\tmulti\n\tmacro\n\tunicode\n\tone-shot\n\ttap-dance\n\trelease-key | release-layer\n\tmwheel-up | mwheel-down | mwheel-left | mwheel-right\n\ton-press-fakekey | on-release-fakekey\n\ton-press-fakekey-delay | on-release-fakekey-delay\n\tcmd",
+            "Unknown action type: {}. Valid types:\n\tlayer-switch\n\tlayer-toggle | layer-while-held\n\ttap-hold | tap-hold-press | tap-hold-release\n\ttap-hold-release-keys | tap-hold-press-keys\n\ttap-hold-opposite-hand\n\tmulti\n\tmacro\n\tunicode\n\tone-shot\n\ttap-dance\n\trelease-key | release-layer\n\tmwheel-up | mwheel-down | mwheel-left | mwheel-right\n\ton-press-fakekey | on-release-fakekey\n\ton-press-fakekey-delay | on-release-fakekey-delay\n\tcmd\n\tswitch-config\n\tcaps-word | caps-word-underscore",
             ac_type
         ),
     }
 }
 
 fn parse_layer_base(ac_params: &[SExpr], layers: &LayerIndexes) -> Result<&'static KanataAction> {
-    Ok(sref(Action::DefaultLayer(
-        layer_idx(ac_params, layers)? * 2,
-    )))
+    let idx = layer_idx(ac_params, layers)?;
+    Ok(unsafe { sref(Action::DefaultLayer(idx)) })
 }
 
 fn parse_layer_toggle(ac_params: &[SExpr], layers: &LayerIndexes) -> Result<&'static KanataAction> {
-    Ok(sref(Action::Layer(layer_idx(ac_params, layers)? * 2 + 1)))
+    let idx = layer_idx(ac_params, layers)?;
+    Ok(unsafe { sref(Action::Layer(idx)) })
 }
 
 fn layer_idx(ac_params: &[SExpr], layers: &LayerIndexes) -> Result<usize> {
@@ -750,22 +1644,110 @@ fn parse_tap_hold(
     if ac_params.len() != 4 {
         bail!("tap-hold expects 4 atoms after it: <tap-timeout> <hold-timeout> <tap-action> <hold-action>, got {}", ac_params.len())
     }
-    let tap_timeout =
-        parse_timeout(&ac_params[0]).map_err(|e| anyhow!("invalid tap-timeout: {}", e))?;
-    let hold_timeout =
-        parse_timeout(&ac_params[1]).map_err(|e| anyhow!("invalid tap-timeout: {}", e))?;
+    let tap_timeout = parse_timeout(&ac_params[0])
+        .map_err(|e| spanned(anyhow!("invalid tap-timeout: {}", e), ac_params[0].span()))?;
+    let hold_timeout = parse_timeout(&ac_params[1])
+        .map_err(|e| spanned(anyhow!("invalid hold-timeout: {}", e), ac_params[1].span()))?;
     let tap_action = parse_action(&ac_params[2], parsed_state)?;
     let hold_action = parse_action(&ac_params[3], parsed_state)?;
     if matches!(tap_action, Action::HoldTap { .. }) {
         bail!("tap-hold does not work in the tap-action of tap-hold")
     }
-    Ok(sref(Action::HoldTap(sref(HoldTapAction {
-        config,
-        tap_hold_interval: tap_timeout,
-        timeout: hold_timeout,
-        tap: *tap_action,
-        hold: *hold_action,
-    }))))
+    Ok(unsafe {
+        sref(Action::HoldTap(sref(HoldTapAction {
+            config,
+            tap_hold_interval: tap_timeout,
+            timeout: hold_timeout,
+            tap: *tap_action,
+            hold: *hold_action,
+        })))
+    })
+}
+
+/// Parse a tap-hold variant that takes a list of keys after the parameters of `tap-hold` and
+/// resolves with the configuration that `config` returns for those keys.
+fn parse_tap_hold_keys(
+    ac_params: &[SExpr],
+    parsed_state: &ParsedState,
+    name: &str,
+    config: fn(&'static [u16]) -> HoldTapConfig,
+) -> Result<&'static KanataAction> {
+    if ac_params.len() != 5 {
+        bail!("{name} expects 5 items after it: <tap-timeout> <hold-timeout> <tap-action> <hold-action> <key list>, got {}", ac_params.len())
+    }
+    let keys = match &ac_params[4] {
+        SExpr::List(keys) => keys
+            .t
+            .iter()
+            .map(|key| match key {
+                SExpr::Atom(k) => match str_to_oscode(&k.t) {
+                    Some(osc) => Ok(u16::from(osc)),
+                    None => bail_span!(k.span, "Unknown key in {name} key list: \"{}\"", k.t),
+                },
+                SExpr::List(l) => bail_span!(l.span, "{name} key list must contain only keys"),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        SExpr::Atom(a) => bail_span!(a.span, "{name} expects a list of keys as its last item"),
+    };
+    parse_tap_hold(
+        &ac_params[..4],
+        parsed_state,
+        config(unsafe { sref(keys) }.as_slice()),
+    )
+}
+
+/// Parse `tap-hold-opposite-hand`, which takes the parameters of `tap-hold` and resolves by the
+/// hands of `defhands`.
+fn parse_tap_hold_opposite_hand(
+    ac_params: &[SExpr],
+    parsed_state: &ParsedState,
+) -> Result<&'static KanataAction> {
+    let hands = parsed_state
+        .hands
+        .ok_or_else(|| anyhow!("tap-hold-opposite-hand requires a defhands entry"))?;
+    let hand = move |coord: (u8, u16)| hands.get(&physical_key(coord)?).copied();
+    let config = HoldTapConfig::OppositeHand(unsafe { sref(hand) });
+    parse_tap_hold(ac_params, parsed_state, config)
+}
+
+/// Returns the physical key at a coordinate of a key event while a tap-hold is waiting, i.e. not a
+/// fake key or chord output.
+fn physical_key((x, y): (u8, u16)) -> Option<u16> {
+    (x == 0 || usize::from(x) >= DEVICE_ROWS_START).then_some(y)
+}
+
+/// `tap-hold-release-keys`: resolve to tap as soon as one of `keys` is pressed, and otherwise to
+/// hold once another key is pressed and released like `tap-hold-release`.
+fn tap_on_keys_else_permissive_hold(keys: &'static [u16]) -> HoldTapConfig {
+    let handler = move |stacked: StackedIter| {
+        let mut pressed = vec![];
+        for s in stacked {
+            let event = s.event();
+            match physical_key(event.coord()) {
+                Some(key) if event.is_press() && keys.contains(&key) => {
+                    return Some(WaitingAction::Tap)
+                }
+                _ if event.is_press() => pressed.push(event.coord()),
+                _ if pressed.contains(&event.coord()) => return Some(WaitingAction::Hold),
+                _ => {}
+            }
+        }
+        None
+    };
+    HoldTapConfig::Custom(unsafe { sref(handler) })
+}
+
+/// `tap-hold-press-keys`: resolve as soon as another key is pressed, to hold if it is one of
+/// `keys`, e.g. the keys of the other hand, and to tap otherwise.
+fn hold_on_keys_else_tap(keys: &'static [u16]) -> HoldTapConfig {
+    let handler = move |stacked: StackedIter| {
+        let event = stacked.map(|s| s.event()).find(|e| e.is_press())?;
+        match physical_key(event.coord()) {
+            Some(key) if keys.contains(&key) => Some(WaitingAction::Hold),
+            _ => Some(WaitingAction::Tap),
+        }
+    };
+    HoldTapConfig::Custom(unsafe { sref(handler) })
 }
 
 fn parse_timeout(a: &SExpr) -> Result<u16> {
@@ -807,10 +1789,10 @@ fn parse_multi(ac_params: &[SExpr], parsed_state: &ParsedState) -> Result<&'stat
     }
 
     if !custom_actions.is_empty() {
-        actions.push(Action::Custom(Box::leak(custom_actions.into_boxed_slice())));
+        actions.push(Action::Custom(unsafe { sref(custom_actions) }.as_slice()));
     }
 
-    Ok(sref(Action::MultipleActions(sref(actions))))
+    Ok(unsafe { sref(Action::MultipleActions(sref(actions))) })
 }
 
 #[test]
@@ -873,9 +1855,11 @@ fn parse_macro(ac_params: &[SExpr], parsed_state: &ParsedState) -> Result<&'stat
         (events, params_remainder) = parse_macro_item(params_remainder, parsed_state)?;
         all_events.append(&mut events);
     }
-    Ok(sref(Action::Sequence {
-        events: sref(all_events),
-    }))
+    Ok(unsafe {
+        sref(Action::Sequence {
+            events: sref(all_events),
+        })
+    })
 }
 
 fn parse_macro_release_cancel(
@@ -883,10 +1867,12 @@ fn parse_macro_release_cancel(
     parsed_state: &ParsedState,
 ) -> Result<&'static KanataAction> {
     let macro_action = parse_macro(ac_params, parsed_state)?;
-    Ok(sref(Action::MultipleActions(sref(vec![
-        *macro_action,
-        Action::Custom(sref_slice(CustomAction::CancelMacroOnRelease)),
-    ]))))
+    Ok(unsafe {
+        sref(Action::MultipleActions(sref(vec![
+            *macro_action,
+            Action::Custom(sref_slice(CustomAction::CancelMacroOnRelease)),
+        ])))
+    })
 }
 
 #[allow(clippy::type_complexity)] // return type is not pub
@@ -1027,17 +2013,49 @@ fn parse_unicode(ac_params: &[SExpr]) -> Result<&'static KanataAction> {
     }
     match &ac_params[0] {
         SExpr::Atom(s) => {
-            if s.t.chars().count() != 1 {
+            let s = unquote(&s.t);
+            if s.chars().count() != 1 {
                 bail!(ERR_STR)
             }
-            Ok(sref(Action::Custom(sref_slice(CustomAction::Unicode(
-                s.t.chars().next().unwrap(),
-            )))))
+            Ok(unsafe {
+                sref(Action::Custom(sref_slice(CustomAction::Unicode(
+                    s.chars().next().unwrap(),
+                ))))
+            })
         }
         _ => bail!(ERR_STR),
     }
 }
 
+fn parse_caps_word(ac_params: &[SExpr], shift_minus: bool) -> Result<&'static KanataAction> {
+    const ERR_STR: &str = "caps-word expects a timeout (1-65535)";
+    if ac_params.len() != 1 {
+        bail!(ERR_STR);
+    }
+    let timeout = parse_timeout(&ac_params[0]).map_err(|e| anyhow!("{ERR_STR}: {e}"))?;
+    if timeout == 0 {
+        bail!(ERR_STR);
+    }
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(CustomAction::CapsWord {
+            timeout,
+            shift_minus,
+        })))
+    })
+}
+
+fn parse_switch_config(ac_params: &[SExpr]) -> Result<&'static KanataAction> {
+    const ERR_STR: &str = "switch-config expects the name of a configuration given to --cfg";
+    match ac_params {
+        [SExpr::Atom(name)] => Ok(unsafe {
+            sref(Action::Custom(sref_slice(CustomAction::SwitchConfig(
+                sref(name.t.clone()).as_str(),
+            ))))
+        }),
+        _ => bail!(ERR_STR),
+    }
+}
+
 fn parse_cmd(ac_params: &[SExpr], is_cmd_enabled: bool) -> Result<&'static KanataAction> {
     const ERR_STR: &str = "cmd expects one or more strings";
     if !is_cmd_enabled {
@@ -1046,21 +2064,19 @@ fn parse_cmd(ac_params: &[SExpr], is_cmd_enabled: bool) -> Result<&'static Kanat
     if ac_params.is_empty() {
         bail!(ERR_STR);
     }
-    Ok(sref(Action::Custom(sref_slice(CustomAction::Cmd(
-        Box::leak(
-            ac_params
-                .iter()
-                .try_fold(Vec::new(), |mut v, p| {
-                    if let SExpr::Atom(s) = p {
-                        v.push(s.t.clone());
-                        Ok(v)
-                    } else {
-                        bail!("{}, found a list", ERR_STR);
-                    }
-                })?
-                .into_boxed_slice(),
-        ),
-    )))))
+    let cmd = ac_params.iter().try_fold(Vec::new(), |mut v, p| {
+        if let SExpr::Atom(s) = p {
+            v.push(unquote(&s.t));
+            Ok(v)
+        } else {
+            bail!("{}, found a list", ERR_STR);
+        }
+    })?;
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(CustomAction::Cmd(
+            sref(cmd).as_slice(),
+        ))))
+    })
 }
 
 fn parse_one_shot(
@@ -1094,11 +2110,13 @@ fn parse_one_shot(
     }
 
     let end_config = OneShotEndConfig::EndOnFirstPress;
-    Ok(sref(Action::OneShot(sref(OneShot {
-        timeout,
-        action,
-        end_config,
-    }))))
+    Ok(unsafe {
+        sref(Action::OneShot(sref(OneShot {
+            timeout,
+            action,
+            end_config,
+        })))
+    })
 }
 
 fn parse_tap_dance(
@@ -1128,12 +2146,12 @@ fn parse_tap_dance(
                 let ac = parse_action(expr, parsed_state)?;
                 actions.push(ac);
             }
-            sref(actions.into_boxed_slice())
+            unsafe { sref(actions.into_boxed_slice()) }
         }
         _ => bail!(ERR_MSG),
     };
 
-    Ok(sref(Action::TapDance(sref(TapDance { timeout, actions }))))
+    Ok(unsafe { sref(Action::TapDance(sref(TapDance { timeout, actions }))) })
 }
 
 fn parse_release_key(
@@ -1146,7 +2164,9 @@ fn parse_release_key(
     }
     let ac = parse_action(&ac_params[0], parsed_state)?;
     match ac {
-        Action::KeyCode(kc) => Ok(sref(Action::ReleaseState(ReleasableState::KeyCode(*kc)))),
+        Action::KeyCode(kc) => {
+            Ok(unsafe { sref(Action::ReleaseState(ReleasableState::KeyCode(*kc))) })
+        }
         _ => bail!("{}, got {:?}", ERR_MSG, ac),
     }
 }
@@ -1159,9 +2179,8 @@ fn parse_release_layer(
     if ac_params.len() != 1 {
         bail!(ERR_MSG);
     }
-    Ok(sref(Action::ReleaseState(ReleasableState::Layer(
-        layer_idx(ac_params, &parsed_state.layer_idxs)? * 2 + 1,
-    ))))
+    let idx = layer_idx(ac_params, &parsed_state.layer_idxs)?;
+    Ok(unsafe { sref(Action::ReleaseState(ReleasableState::Layer(idx))) })
 }
 
 fn parse_defsrc_layer(defsrc: &[SExpr], mapping_order: &[usize]) -> [KanataAction; KEYS_IN_ROW] {
@@ -1216,10 +2235,12 @@ fn parse_fake_key_op(
     parsed_state: &ParsedState,
 ) -> Result<&'static KanataAction> {
     let (coord, action) = parse_fake_key_op_coord_action(ac_params, parsed_state)?;
-    Ok(sref(Action::Custom(sref_slice(CustomAction::FakeKey {
-        coord,
-        action,
-    }))))
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(CustomAction::FakeKey {
+            coord,
+            action,
+        })))
+    })
 }
 
 fn parse_on_release_fake_key_op(
@@ -1227,9 +2248,12 @@ fn parse_on_release_fake_key_op(
     parsed_state: &ParsedState,
 ) -> Result<&'static KanataAction> {
     let (coord, action) = parse_fake_key_op_coord_action(ac_params, parsed_state)?;
-    Ok(sref(Action::Custom(sref_slice(
-        CustomAction::FakeKeyOnRelease { coord, action },
-    ))))
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(CustomAction::FakeKeyOnRelease {
+            coord,
+            action,
+        })))
+    })
 }
 
 fn parse_fake_key_op_coord_action(
@@ -1287,10 +2311,12 @@ fn parse_delay(ac_params: &[SExpr], is_release: bool) -> Result<&'static KanataA
         .transpose()
         .map_err(|e| anyhow!("{ERR_MSG}: {e}"))?
         .ok_or_else(|| anyhow!("{ERR_MSG}"))?;
-    Ok(sref(Action::Custom(sref_slice(match is_release {
-        false => CustomAction::Delay(delay),
-        true => CustomAction::DelayOnRelease(delay),
-    }))))
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(match is_release {
+            false => CustomAction::Delay(delay),
+            true => CustomAction::DelayOnRelease(delay),
+        })))
+    })
 }
 
 fn parse_mwheel(ac_params: &[SExpr], direction: MWheelDirection) -> Result<&'static KanataAction> {
@@ -1318,50 +2344,237 @@ fn parse_mwheel(ac_params: &[SExpr], direction: MWheelDirection) -> Result<&'sta
             _ => None,
         })
         .ok_or_else(|| anyhow!("{ERR_MSG}: distance should be 1-30000"))?;
-    Ok(sref(Action::Custom(sref_slice(CustomAction::MWheel {
-        direction,
-        interval,
-        distance,
-    }))))
+    Ok(unsafe {
+        sref(Action::Custom(sref_slice(CustomAction::MWheel {
+            direction,
+            interval,
+            distance,
+        })))
+    })
+}
+
+/// Parse the key of a `deflayermap` item. Returns its index into a layer row, which must be in
+/// `mapping_order` and not in the `items` parsed so far.
+fn parse_layermap_key(
+    key: &SExpr,
+    mapping_order: &[usize],
+    items: &[(&SExpr, usize)],
+) -> Result<usize> {
+    let name = match key {
+        SExpr::Atom(a) => &a.t,
+        _ => bail_span!(key.span(), "deflayermap expects a key name, found a list"),
+    };
+    let i = match str_to_oscode(name) {
+        Some(osc) => usize::from(osc),
+        None => bail_span!(key.span(), "Unknown key in deflayermap: \"{}\"", name),
+    };
+    if !mapping_order.contains(&i) {
+        bail_span!(
+            key.span(),
+            "Key in deflayermap is not in defsrc: \"{}\"",
+            name
+        )
+    }
+    if items.iter().any(|&(_, j)| j == i) {
+        bail_span!(
+            key.span(),
+            "Repeat mapping of key in deflayermap: \"{}\"",
+            name
+        )
+    }
+    Ok(i)
+}
+
+/// Returns the parent layer name of a `deflayer` or `deflayermap` expression whose name is written
+/// as `(<name> inherits <parent layer>)`.
+fn layer_parent(layer: &[SExpr]) -> Option<&Spanned<String>> {
+    match layer.get(1)?.list()? {
+        [_, _, SExpr::Atom(parent)] => Some(parent),
+        _ => None,
+    }
 }
 
-/// Mutates `layers::LAYERS` using the inputs.
-fn parse_layers(parsed_state: &ParsedState) -> Result<Box<KanataLayers>> {
-    let mut layers_cfg = new_layers();
+/// Returns the layer indexes in an order where every layer comes after the layer it inherits
+/// from, along with the index of that parent. Unknown parents and inheritance cycles are added to
+/// `errors` and the layers with them are treated as not inheriting.
+fn layer_resolution_order(
+    parsed_state: &ParsedState,
+    errors: &mut Vec<anyhow::Error>,
+) -> Vec<(usize, Option<usize>)> {
+    let layer_count = parsed_state.layer_exprs.len();
+    let mut parents = vec![None; layer_count];
     for (layer_level, layer) in parsed_state.layer_exprs.iter().enumerate() {
-        // skip deflayer and name
-        for (i, ac) in layer.iter().skip(2).enumerate() {
-            let ac = parse_action(ac, parsed_state)?;
-            layers_cfg[layer_level * 2][0][parsed_state.mapping_order[i]] = *ac;
-            layers_cfg[layer_level * 2 + 1][0][parsed_state.mapping_order[i]] = *ac;
-        }
-        for (i, (layer_action, defsrc_action)) in layers_cfg[layer_level * 2][0]
-            .iter_mut()
-            .zip(parsed_state.defsrc_layer)
-            .enumerate()
-        {
-            if *layer_action == Action::Trans {
-                *layer_action = defsrc_action;
+        if let Some(parent) = layer_parent(layer) {
+            match parsed_state.layer_idxs.get(&parent.t) {
+                Some(&idx) => parents[layer_level] = Some(idx),
+                None => errors.push(
+                    SpannedError {
+                        msg: format!("layer inherits from unknown layer {}", parent.t),
+                        span: parent.span,
+                    }
+                    .into(),
+                ),
+            }
+        }
+    }
+
+    let mut order = Vec::with_capacity(layer_count);
+    let mut resolved = vec![false; layer_count];
+    for layer_level in 0..layer_count {
+        // Walk up to the first ancestor that is resolved or has no parent, then resolve the
+        // walked layers from the top down.
+        let mut chain = vec![layer_level];
+        while let Some(parent) = parents[*chain.last().unwrap()] {
+            if resolved[parent] {
+                break;
             }
-            // If key is unmapped in defsrc as well, default it to the OsCode for that index if the
-            // configuration says to do so.
-            if *layer_action == Action::Trans {
-                *layer_action = OsCode::from_u16(i as u16)
-                    .and_then(|osc| match KeyCode::from(osc) {
-                        KeyCode::No => None,
-                        kc => Some(Action::KeyCode(kc)),
-                    })
-                    .unwrap_or(Action::Trans);
+            if let Some(i) = chain.iter().position(|&l| l == parent) {
+                let layer_name = |l: usize| {
+                    parsed_state
+                        .layer_idxs
+                        .iter()
+                        .find(|(_, &idx)| idx == l)
+                        .map(|(name, _)| name.as_str())
+                        .unwrap_or_default()
+                };
+                let cycle = chain[i..]
+                    .iter()
+                    .chain(std::iter::once(&parent))
+                    .map(|&l| layer_name(l))
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                // Break the cycle at the layer that closes it, so it is resolved first.
+                let last = *chain.last().unwrap();
+                errors.push(
+                    SpannedError {
+                        msg: format!("layer inheritance cycle: {cycle}"),
+                        span: parsed_state.layer_exprs[last][1].span(),
+                    }
+                    .into(),
+                );
+                parents[last] = None;
+                break;
+            }
+            chain.push(parent);
+        }
+        for &l in chain.iter().rev() {
+            if !resolved[l] {
+                resolved[l] = true;
+                order.push((l, parents[l]));
             }
         }
+    }
+    order
+}
+
+/// Returns the layers built from the inputs, followed by the layer that transparent keys on the
+/// default layer fall back to. A layer that inherits from another layer starts out as a copy of
+/// it. Returns the errors of all invalid layer items.
+fn parse_layers(parsed_state: &ParsedState) -> Result<KanataLayers> {
+    let layer_count = parsed_state.layer_exprs.len();
+    let mut layers_cfg = KanataLayers::new(layer_count + 1, parsed_state.devices.len());
+    let mut errors = vec![];
+    for (layer_level, parent) in layer_resolution_order(parsed_state, &mut errors) {
+        if let Some(parent) = parent {
+            layers_cfg.copy_layer(parent, layer_level);
+        }
+        let layer = parsed_state.layer_exprs[layer_level];
+        fill_layer_row(
+            &mut layers_cfg[layer_level][0],
+            Some(layer.as_slice()),
+            &parsed_state.mapping_order,
+            parsed_state,
+            &mut errors,
+        );
+        for (i, device) in parsed_state.devices.iter().enumerate() {
+            fill_layer_row(
+                &mut layers_cfg[layer_level][DEVICE_ROWS_START + i],
+                device.layer_exprs.get(&layer_level).copied(),
+                &device.mapping_order,
+                parsed_state,
+                &mut errors,
+            );
+        }
         for (y, action) in parsed_state.fake_keys.values() {
             let (x, y) = get_fake_key_coords(*y);
             layers_cfg[layer_level][x as usize][y as usize] = **action;
         }
+        for (y, action) in parsed_state.chord_actions.iter() {
+            let (x, y) = get_fake_key_coords(*y);
+            layers_cfg[layer_level][x as usize][y as usize] = **action;
+        }
+    }
+    let src_layer = &mut layers_cfg[layer_count];
+    fill_src_row(&mut src_layer[0], &parsed_state.defsrc_layer);
+    for (i, device) in parsed_state.devices.iter().enumerate() {
+        fill_src_row(&mut src_layer[DEVICE_ROWS_START + i], &device.defsrc_layer);
     }
+    combine(errors)?;
     Ok(layers_cfg)
 }
 
+/// Fill a layer `row` from a `deflayer` or `deflayermap` expression. A missing expression, like
+/// the keys that a `deflayermap` does not list, is treated like `_`, which keeps the action that
+/// the row already has. Errors of invalid items are added to `errors`.
+fn fill_layer_row(
+    row: &mut [KanataAction; KEYS_IN_ROW],
+    layer: Option<&[SExpr]>,
+    mapping_order: &[usize],
+    parsed_state: &ParsedState,
+    errors: &mut Vec<anyhow::Error>,
+) {
+    let layer = layer.unwrap_or_default();
+    // skip deflayer and name
+    let items: Vec<(&SExpr, usize)> = if layer.first().and_then(|e| e.atom()) == Some("deflayermap")
+    {
+        let mut items = vec![];
+        for pair in layer[2..].chunks_exact(2) {
+            match parse_layermap_key(&pair[0], mapping_order, &items) {
+                Ok(i) => items.push((&pair[1], i)),
+                Err(e) => errors.push(e),
+            }
+        }
+        items
+    } else {
+        layer
+            .iter()
+            .skip(2)
+            .zip(mapping_order.iter().copied())
+            .collect()
+    };
+    for (ac, i) in items {
+        let ac = match parse_action(ac, parsed_state) {
+            Ok(ac) => ac,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if *ac == Action::Trans {
+            continue;
+        }
+        row[i] = *ac;
+    }
+}
+
+/// Fill a `row` of the layer that transparent keys on the default layer fall back to from the
+/// `defsrc` actions of the row.
+fn fill_src_row(row: &mut [KanataAction; KEYS_IN_ROW], defsrc_layer: &[KanataAction; KEYS_IN_ROW]) {
+    for (i, (src_action, defsrc_action)) in row.iter_mut().zip(defsrc_layer).enumerate() {
+        *src_action = *defsrc_action;
+        // If key is unmapped in defsrc as well, default it to the OsCode for that index if the
+        // configuration says to do so.
+        if *src_action == Action::Trans {
+            *src_action = OsCode::from_u16(i as u16)
+                .and_then(|osc| match KeyCode::from(osc) {
+                    KeyCode::No => None,
+                    kc => Some(Action::KeyCode(kc)),
+                })
+                .unwrap_or(Action::Trans);
+        }
+    }
+}
+
 fn parse_sequences(exprs: &[&Vec<SExpr>], parsed_state: &ParsedState) -> Result<KeySeqsToFKeys> {
     const ERR_MSG: &str = "defseq expects pairs of parameters: <fake_key_name> <key_list>";
     let mut sequences = Trie::new();
@@ -1414,12 +2627,282 @@ fn parse_sequences(exprs: &[&Vec<SExpr>], parsed_state: &ParsedState) -> Result<
     Ok(sequences)
 }
 
+/// Parse chord groups from expressions starting with defchords. The output action of every chord
+/// is placed on a virtual key in the fake key row, after the fake keys.
+fn parse_chord_groups(
+    exprs: &[&Vec<SExpr>],
+    mapped_keys: &MappedKeys,
+    parsed_state: &mut ParsedState,
+) -> Result<ChordGroups> {
+    const ERR_MSG: &str = "defchords expects a group name and a timeout, followed by pairs of parameters: <key_list> <action>";
+    let mut groups = ChordGroups::new();
+    let mut keys_in_groups = HashSet::default();
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defchords")?;
+        let name = subexprs
+            .next()
+            .ok_or_else(|| anyhow!(ERR_MSG))?
+            .atom()
+            .ok_or_else(|| anyhow!("{ERR_MSG}: got a list for the group name"))?
+            .to_owned();
+        if groups.iter().any(|g: &ChordGroup| g.name == name) {
+            bail!("Duplicate defchords group: {name}");
+        }
+        let timeout = parse_timeout(subexprs.next().ok_or_else(|| anyhow!(ERR_MSG))?)
+            .map_err(|e| anyhow!("invalid timeout for defchords {name}: {e}"))?;
+
+        let mut defs: Vec<ChordDef> = vec![];
+        let mut keys = HashSet::default();
+        while let Some(key_list) = subexprs.next() {
+            let action = subexprs
+                .next()
+                .ok_or_else(|| anyhow!("{ERR_MSG}: missing action for {key_list:?} in {name}"))?;
+            let key_list = key_list
+                .list()
+                .ok_or_else(|| anyhow!("{ERR_MSG}: got a non-list for key_list in {name}"))?;
+            if !(2..=MAX_KEYS_IN_CHORD).contains(&key_list.len()) {
+                bail!(
+                    "chord {key_list:?} in {name} must have between 2 and {MAX_KEYS_IN_CHORD} keys"
+                );
+            }
+            let mut chord_keys = vec![];
+            for key in key_list {
+                let osc = key
+                    .atom()
+                    .and_then(str_to_oscode)
+                    .ok_or_else(|| anyhow!("{ERR_MSG}: invalid key in key_list {key:?}"))?;
+                if !mapped_keys.contains(&osc) {
+                    bail!("chord key {key:?} in {name} must also be declared in defsrc");
+                }
+                if keys_in_groups.contains(&osc) {
+                    bail!("chord key {key:?} is used in more than one defchords group");
+                }
+                let coord = (0, u16::from(osc));
+                if chord_keys.contains(&coord) {
+                    bail!("chord {key_list:?} in {name} contains {key:?} more than once");
+                }
+                chord_keys.push(coord);
+                keys.insert(osc);
+            }
+            if defs.iter().any(|(_, other_keys)| {
+                other_keys.len() == chord_keys.len()
+                    && other_keys.iter().all(|k| chord_keys.contains(k))
+            }) {
+                bail!("chord {key_list:?} is defined more than once in {name}");
+            }
+            let action = parse_action(action, parsed_state)?;
+            let y = parsed_state.fake_keys.len() + parsed_state.chord_actions.len();
+            parsed_state.chord_actions.push((y, action));
+            defs.push((
+                get_fake_key_coords(y),
+                unsafe { sref(chord_keys) }.as_slice(),
+            ));
+        }
+        if defs.is_empty() {
+            bail!("{ERR_MSG}: {name} has no chords");
+        }
+        if defs.len() > MAX_CHORDS_IN_GROUP {
+            bail!(
+                "Maximum number of chords in a defchords group is {MAX_CHORDS_IN_GROUP}, found {} in {name}",
+                defs.len()
+            );
+        }
+        keys_in_groups.extend(keys.iter().copied());
+        // keyberon uses the first chord whose keys are all pressed, so chords with more keys come
+        // first. Otherwise a chord would always be used instead of a larger one that contains it.
+        defs.sort_by_key(|(_, keys)| std::cmp::Reverse(keys.len()));
+        let defs: &'static [ChordDef] = unsafe { sref(defs) }.as_slice();
+        groups.push(ChordGroup {
+            name,
+            timeout,
+            keys: keys.into_iter().map(u16::from).collect(),
+            defs,
+            chording: Chording::from_slice(defs),
+        });
+    }
+    let num_virtual_keys = parsed_state.fake_keys.len() + parsed_state.chord_actions.len();
+    if num_virtual_keys > KEYS_IN_ROW {
+        bail!("Maximum number of fake keys and chords combined is {KEYS_IN_ROW}, found {num_virtual_keys}");
+    }
+    Ok(groups)
+}
+
+/// Parse the `defhands` expression, which tags `defsrc` keys with the hand that types them:
+///
+///     (defhands
+///       (left q w e r t a s d f g)
+///       (right y u i o p h j k l ;)
+///     )
+fn parse_hands(
+    exprs: &[&Vec<SExpr>],
+    mapped_keys: &MappedKeys,
+) -> Result<Option<&'static HashMap<u16, Hand>>> {
+    const ERR_MSG: &str = "defhands expects lists of keys starting with left or right";
+    let expr = match exprs {
+        [] => return Ok(None),
+        [expr] => expr,
+        _ => bail!("Only one defhands is allowed in the configuration"),
+    };
+    let mut hands = HashMap::default();
+    for subexpr in check_first_expr(expr.iter(), "defhands")? {
+        let (hand, keys) = match subexpr {
+            SExpr::List(l) => match l.t.split_first() {
+                Some((SExpr::Atom(a), keys)) if a.t == "left" => (Hand::Left, keys),
+                Some((SExpr::Atom(a), keys)) if a.t == "right" => (Hand::Right, keys),
+                _ => bail_span!(l.span, "{ERR_MSG}"),
+            },
+            SExpr::Atom(a) => bail_span!(a.span, "{ERR_MSG}"),
+        };
+        for key in keys {
+            let key = match key {
+                SExpr::Atom(k) => k,
+                SExpr::List(l) => bail_span!(l.span, "{ERR_MSG}"),
+            };
+            let osc = match str_to_oscode(&key.t) {
+                Some(osc) => osc,
+                None => bail_span!(key.span, "Unknown key in defhands: \"{}\"", key.t),
+            };
+            if !mapped_keys.contains(&osc) {
+                bail_span!(key.span, "Key in defhands is not in defsrc: \"{}\"", key.t);
+            }
+            if hands.insert(u16::from(osc), hand).is_some() {
+                bail_span!(
+                    key.span,
+                    "Key in defhands is listed more than once: \"{}\"",
+                    key.t
+                );
+            }
+        }
+    }
+    Ok(Some(unsafe { sref(hands) }))
+}
+
+/// Parse `defdevice` expressions, which give the input devices they match their own `defsrc` and
+/// layers:
+///
+///     (defdevice <name>
+///       (name <device name>) (path <device path>) (id <vendor id> <product id>)
+///       (defsrc ...)
+///       (deflayer <top-level layer name> ...)
+///     )
+///
+/// Each block gets its own row in the keyberon layers so that layer changes apply to all devices.
+fn parse_defdevices<'a>(
+    exprs: &[&'a Vec<SExpr>],
+    defcfg: &HashMap<String, String>,
+    parsed_state: &mut ParsedState<'a>,
+) -> Result<Vec<DeviceCfg>> {
+    if exprs.len() > MAX_DEVICE_CFGS {
+        bail!(
+            "Exceeded the maximum defdevice count of {MAX_DEVICE_CFGS}: each defdevice needs a layout row and keyberon supports 256 rows"
+        )
+    }
+    let mut devices: Vec<DeviceCfg> = vec![];
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defdevice")?;
+        let name = subexprs
+            .next()
+            .and_then(|e| e.atom())
+            .ok_or_else(|| anyhow!("defdevice requires a name as its first parameter"))?
+            .to_owned();
+        if devices.iter().any(|d| d.name == name) {
+            bail!("Duplicate defdevice name: {name}")
+        }
+        let mut matchers = vec![];
+        let mut src = None;
+        let mut layer_exprs = HashMap::default();
+        for subexpr in subexprs {
+            let list = subexpr.list().ok_or_else(|| {
+                anyhow!("defdevice {name}: expected lists after the name, found {subexpr:?}")
+            })?;
+            match list.first().and_then(|e| e.atom()).unwrap_or_default() {
+                "name" | "path" | "id" => matchers.push(
+                    parse_device_matcher(list).map_err(|e| anyhow!("defdevice {name}: {e}"))?,
+                ),
+                "defsrc" => {
+                    if src.replace(list).is_some() {
+                        bail!("defdevice {name}: only one defsrc is allowed")
+                    }
+                }
+                "deflayer" => {
+                    let layer_name = list.get(1).and_then(|e| e.atom()).ok_or_else(|| {
+                        anyhow!("defdevice {name}: deflayer requires a name and keys")
+                    })?;
+                    let idx = *parsed_state.layer_idxs.get(layer_name).ok_or_else(|| {
+                        anyhow!("defdevice {name}: layer {layer_name} is not defined at the top level")
+                    })?;
+                    if layer_exprs.insert(idx, list).is_some() {
+                        bail!("defdevice {name}: duplicate deflayer {layer_name}")
+                    }
+                }
+                _ => bail!(
+                    "defdevice {name}: expected one of name, path, id, defsrc or deflayer, found {subexpr:?}"
+                ),
+            }
+        }
+        if matchers.is_empty() {
+            bail!("defdevice {name}: at least one of name, path or id is required")
+        }
+        let src = src.ok_or_else(|| anyhow!("defdevice {name}: defsrc is missing"))?;
+        let (mapped_keys, mapping_order) = parse_defsrc(src, defcfg)?;
+        for layer in layer_exprs.values() {
+            let num_actions = layer.len() - 2;
+            if num_actions != mapping_order.len() {
+                bail!(
+                    "defdevice {name}: layer {:?} has {} items, but requires {} to match its defsrc",
+                    layer[1],
+                    num_actions,
+                    mapping_order.len()
+                )
+            }
+        }
+        let defsrc_layer = parse_defsrc_layer(src, &mapping_order);
+        parsed_state.devices.push(ParsedDevice {
+            mapping_order,
+            defsrc_layer,
+            layer_exprs,
+        });
+        devices.push(DeviceCfg {
+            name,
+            matchers,
+            mapped_keys,
+            key_outputs: KeyOutputs::new(),
+        });
+    }
+    Ok(devices)
+}
+
+/// Parse a `(name ...)`, `(path ...)` or `(id ...)` list of a `defdevice` block.
+fn parse_device_matcher(list: &[SExpr]) -> Result<DeviceMatcher> {
+    let params = list[1..]
+        .iter()
+        .map(|e| {
+            e.atom()
+                .ok_or_else(|| anyhow!("expected atoms, found {e:?}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let parse_id = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    match (list[0].atom().unwrap_or_default(), params.as_slice()) {
+        ("name", [name]) => Ok(DeviceMatcher::Name(unquote(name))),
+        ("path", [path]) => Ok(DeviceMatcher::Path(unquote(path))),
+        ("id", [vendor, product]) => Ok(DeviceMatcher::Id {
+            vendor: parse_id(vendor).map_err(|e| anyhow!("invalid vendor id {vendor}: {e}"))?,
+            product: parse_id(product).map_err(|e| anyhow!("invalid product id {product}: {e}"))?,
+        }),
+        ("id", _) => bail!("id expects a vendor id and a product id"),
+        (kind, _) => bail!("{kind} expects a single parameter"),
+    }
+}
+
 /// Creates a `KeyOutputs` from `layers::LAYERS`.
-fn create_key_outputs(layers: &KanataLayers) -> KeyOutputs {
+fn create_key_outputs(layers: &KanataLayers, row: usize) -> KeyOutputs {
     let mut outs = KeyOutputs::new();
     for layer in layers.iter() {
         let mut layer_outputs = HashMap::default();
-        for (i, action) in layer[0].iter().enumerate() {
+        for (i, action) in layer[row].iter().enumerate() {
             let osc_slot = match i.try_into() {
                 Ok(i) => i,
                 Err(_) => continue,
@@ -1480,7 +2963,339 @@ fn add_key_output_from_action_to_key_pos(
     };
 }
 
-/// Create a layout from `layers::LAYERS`.
-fn create_layout(layers: Box<KanataLayers>) -> KanataLayout {
-    Layout::new(Box::leak(layers))
+/// Create a layout from the layers returned by `parse_layers`.
+fn create_layout(layers: KanataLayers) -> KanataLayout {
+    let rows_per_layer = layers.rows_per_layer();
+    let rows = unsafe { sref(layers.into_rows()) };
+    let (layers, src_layer) = rows.split_at(rows.len() - rows_per_layer);
+    Layout::new_with_src_layer(layers, src_layer)
+}
+
+#[test]
+fn report_all_errors_with_locations() {
+    let e = parse_cfg(&std::path::PathBuf::from("./test_cfgs/multiple_errors.kbd"))
+        .map(|_| ())
+        .unwrap_err()
+        .to_string();
+    for expected in [
+        "Referenced unknown alias nope",
+        "multiple_errors.kbd:5:13",
+        "invalid hold-timeout",
+        "multiple_errors.kbd:6:19",
+        "Could not parse: \"blah\"",
+        "multiple_errors.kbd:8:22\n  |\n8 | (deflayer base @x @y blah)\n  |                      ^^^^",
+        "layer other has 5 items",
+        "multiple_errors.kbd:9:2",
+    ] {
+        assert!(e.contains(expected), "missing {expected:?} in:\n{e}");
+    }
+    // Uses of an invalid alias are not reported again.
+    assert!(!e.contains("unknown alias x"), "real e: {e}");
+}
+
+#[test]
+fn parse_include() {
+    let RawCfg {
+        mapped_keys: src,
+        layer_info,
+        layers,
+        ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/include/main.kbd")).unwrap();
+    assert!(src.contains(&OsCode::KEY_A));
+    assert_eq!(layer_info[1].name, "nav");
+    assert_eq!(layers[0][0][usize::from(OsCode::KEY_A)], Action::Layer(1));
+}
+
+#[test]
+fn disallow_include_cycle() {
+    match parse_cfg(&std::path::PathBuf::from("./test_cfgs/include/cycle.kbd"))
+        .map_err(|e| e.to_string())
+    {
+        Ok(_) => panic!("include cycle was Ok'd"),
+        Err(e) => {
+            assert!(e.contains("include cycle"), "real e: {e}");
+            assert!(e.contains("cycle_inner.kbd:2:1"), "real e: {e}");
+        }
+    }
+}
+
+#[test]
+fn include_errors_name_included_file() {
+    match parse_cfg(&std::path::PathBuf::from("./test_cfgs/include/error.kbd"))
+        .map_err(|e| e.to_string())
+    {
+        Ok(_) => panic!("unknown key in included file was Ok'd"),
+        Err(e) => assert!(
+            e.contains("Unknown key in defsrc") && e.contains("error_inner.kbd:2:3"),
+            "real e: {e}"
+        ),
+    }
+}
+
+#[test]
+fn parse_deflayermap() {
+    let RawCfg {
+        layer_info, layers, ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/layermap.kbd")).unwrap();
+    assert_eq!(layer_info[1].name, "nav");
+    let nav = &layers[1][0];
+    assert_eq!(
+        nav[usize::from(OsCode::KEY_H)],
+        Action::KeyCode(KeyCode::Left)
+    );
+    assert_eq!(
+        nav[usize::from(OsCode::KEY_L)],
+        Action::KeyCode(KeyCode::Right)
+    );
+    // Unlisted keys are transparent.
+    assert_eq!(nav[usize::from(OsCode::KEY_A)], Action::Trans);
+}
+
+#[test]
+fn report_deflayermap_errors() {
+    let e = parse_cfg(&std::path::PathBuf::from("./test_cfgs/layermap_errors.kbd"))
+        .map(|_| ())
+        .unwrap_err()
+        .to_string();
+    for expected in [
+        "Key in deflayermap is not in defsrc: \"x\"",
+        "layermap_errors.kbd:9:3",
+        "Repeat mapping of key in deflayermap: \"j\"",
+        "layermap_errors.kbd:11:3",
+        "layer odd has 3 items, but deflayermap requires pairs of a key and an action",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+}
+
+#[test]
+fn parse_layer_inheritance() {
+    let RawCfg {
+        layer_info, layers, ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/inherit.kbd")).unwrap();
+    assert_eq!(layer_info[0].name, "colemak");
+    let colemak = &layers[0][0];
+    assert_eq!(colemak[usize::from(OsCode::KEY_A)], Action::Layer(2));
+    assert_eq!(
+        colemak[usize::from(OsCode::KEY_E)],
+        Action::KeyCode(KeyCode::F)
+    );
+    // Overrides of the parent layer are not inherited by its siblings.
+    let nav = &layers[2][0];
+    assert_eq!(nav[usize::from(OsCode::KEY_A)], Action::Layer(2));
+    assert_eq!(
+        nav[usize::from(OsCode::KEY_E)],
+        Action::KeyCode(KeyCode::Up)
+    );
+    assert_eq!(nav[usize::from(OsCode::KEY_R)], Action::KeyCode(KeyCode::R));
+}
+
+#[test]
+fn report_layer_inheritance_errors() {
+    let e = parse_cfg(&std::path::PathBuf::from("./test_cfgs/inherit_errors.kbd"))
+        .map(|_| ())
+        .unwrap_err()
+        .to_string();
+    for expected in [
+        "layer inheritance cycle: one -> two -> one",
+        "inherit_errors.kbd:9:11",
+        "layer inherits from unknown layer missing",
+        "inherit_errors.kbd:11:27",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+}
+
+#[test]
+fn parse_defvar() {
+    let RawCfg {
+        items: cfg,
+        layers,
+        def_texts,
+        ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/defvar.kbd")).unwrap();
+    assert_eq!(cfg["sequence-timeout"], "500");
+    let base = &layers[0][0];
+    for (osc, tap, hold) in [
+        (OsCode::KEY_A, KeyCode::A, KeyCode::LCtrl),
+        (OsCode::KEY_S, KeyCode::S, KeyCode::LAlt),
+    ] {
+        match base[usize::from(osc)] {
+            Action::HoldTap(HoldTapAction {
+                timeout,
+                tap_hold_interval,
+                tap: Action::KeyCode(t),
+                hold: Action::KeyCode(h),
+                ..
+            }) => {
+                assert_eq!((*timeout, *tap_hold_interval), (200, 200));
+                assert_eq!((*t, *h), (tap, hold));
+            }
+            ref other => panic!("expected a tap-hold, got {other:?}"),
+        }
+    }
+    assert_eq!(base[usize::from(OsCode::KEY_H)], Action::Layer(1));
+    assert_eq!(def_texts.variables["nav"], "(layer-while-held nav)");
+}
+
+#[test]
+fn report_defvar_errors() {
+    let e = parse_cfg(&std::path::PathBuf::from("./test_cfgs/defvar_errors.kbd"))
+        .map(|_| ())
+        .unwrap_err()
+        .to_string();
+    for expected in [
+        "variable timeout is defined more than once",
+        "defvar_errors.kbd:5:3",
+        "variable names must be atoms without a leading $",
+        "defvar_errors.kbd:6:3",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+}
+
+#[cfg(feature = "cmd")]
+#[test]
+fn cmd_arguments_keep_undefined_variables() {
+    let RawCfg { layers, .. } =
+        parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/defvar_cmd.kbd")).unwrap();
+    match layers[0][0][usize::from(OsCode::KEY_A)] {
+        Action::Custom(&[CustomAction::Cmd(args)]) => {
+            assert_eq!(*args, ["echo", "hello", "$HOME"]);
+        }
+        ref other => panic!("expected a cmd, got {other:?}"),
+    }
+}
+
+#[test]
+fn parse_deftemplate() {
+    let RawCfg {
+        layers, def_texts, ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/template.kbd")).unwrap();
+    let base = &layers[0][0];
+    for (osc, tap, hold) in [
+        (OsCode::KEY_A, KeyCode::A, KeyCode::LGui),
+        (OsCode::KEY_SEMICOLON, KeyCode::SColon, KeyCode::RGui),
+        (OsCode::KEY_S, KeyCode::S, KeyCode::LAlt),
+    ] {
+        match base[usize::from(osc)] {
+            Action::HoldTap(HoldTapAction {
+                timeout: 200,
+                tap: Action::KeyCode(t),
+                hold: Action::KeyCode(h),
+                ..
+            }) => assert_eq!((*t, *h), (tap, hold)),
+            ref other => panic!("expected a tap-hold, got {other:?}"),
+        }
+    }
+    assert_eq!(
+        base[usize::from(OsCode::KEY_D)],
+        Action::KeyCode(KeyCode::D)
+    );
+    assert_eq!(def_texts.aliases["s"], "(t! alias-hrm s lalt)");
+}
+
+#[test]
+fn report_deftemplate_errors() {
+    let e = parse_cfg(&std::path::PathBuf::from("./test_cfgs/template_errors.kbd"))
+        .map(|_| ())
+        .unwrap_err()
+        .to_string();
+    for expected in [
+        "unknown template loop, templates can only use templates defined before them",
+        "template_errors.kbd:8:7",
+        "template hrm expects 2 arguments (key mod), found 1",
+        "template_errors.kbd:14:3",
+        "unknown template missing",
+        "template_errors.kbd:15:7",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+
+    // Errors in arguments point at the argument.
+    let e = parse_cfg(&std::path::PathBuf::from(
+        "./test_cfgs/template_argument_error.kbd",
+    ))
+    .map(|_| ())
+    .unwrap_err()
+    .to_string();
+    for expected in [
+        "Could not parse: \"lmetx\"",
+        "template_argument_error.kbd:7:26",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+}
+
+#[test]
+fn parse_conditional_sections() {
+    std::env::set_var("KANATA_TEST_HOST", "box1");
+    std::env::remove_var("KANATA_TEST_UNSET");
+    let RawCfg { layer_info, .. } =
+        parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/conditional.kbd")).unwrap();
+    let platform_layer = match std::env::consts::OS {
+        "linux" => "linux",
+        _ => "other",
+    };
+    assert_eq!(
+        layer_info
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect::<Vec<_>>(),
+        vec![platform_layer, "box1", "box1-nested", "unset"]
+    );
+
+    let e = parse_cfg(&std::path::PathBuf::from(
+        "./test_cfgs/conditional_errors.kbd",
+    ))
+    .map(|_| ())
+    .unwrap_err()
+    .to_string();
+    for expected in [
+        "Unknown platform, valid platforms are: linux, windows, macos",
+        "conditional_errors.kbd:7:18",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unset_hostname_is_the_machine_name() {
+    std::env::remove_var("HOSTNAME");
+    let mut buf = [0u8; 256];
+    let host = nix::unistd::gethostname(&mut buf).unwrap();
+    assert_eq!(env_var("HOSTNAME"), host.to_string_lossy());
+    assert!(!env_var("HOSTNAME").is_empty());
+}
+
+#[test]
+fn parse_quoted_strings_and_block_comments() {
+    let RawCfg {
+        layer_info, layers, ..
+    } = parse_cfg_raw(&std::path::PathBuf::from("./test_cfgs/strings.kbd")).unwrap();
+    assert_eq!(layer_info.len(), 1);
+    let base = &layers[0][0];
+    for (osc, c) in [(OsCode::KEY_A, '('), (OsCode::KEY_B, '"')] {
+        match base[usize::from(osc)] {
+            Action::Custom([CustomAction::Unicode(u)]) => assert_eq!(*u, c),
+            ref other => panic!("expected unicode {c}, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn report_defhands_errors() {
+    let e = parse_cfg(&std::path::PathBuf::from(
+        "./test_cfgs/opposite_hand_errors.kbd",
+    ))
+    .map(|_| ())
+    .unwrap_err()
+    .to_string();
+    for expected in [
+        "Key in defhands is not in defsrc: \"d\"",
+        "opposite_hand_errors.kbd:6:13",
+    ] {
+        assert!(e.contains(expected), "expected {expected}, real e: {e}");
+    }
 }
@@ -3,14 +3,14 @@ use std::ops::Index;
 use std::str::Bytes;
 use std::{cmp, iter};
 
-use anyhow::anyhow;
-
-type ParseError = Spanned<String>;
+pub type ParseError = Spanned<String>;
 
 type ParseResult<T> = Result<T, ParseError>;
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Span {
+    /// The file the span is in, for configurations made up of several files.
+    file_id: usize,
     start: usize,
     end: usize,
 }
@@ -29,7 +29,19 @@ impl LineCol {
 impl Span {
     fn new(start: usize, end: usize) -> Span {
         assert!(start <= end);
-        Span { start, end }
+        Span {
+            file_id: 0,
+            start,
+            end,
+        }
+    }
+
+    fn in_file(self, file_id: usize) -> Span {
+        Span { file_id, ..self }
+    }
+
+    pub fn file_id(&self) -> usize {
+        self.file_id
     }
 
     pub fn start(&self) -> usize {
@@ -43,7 +55,7 @@ impl Span {
     pub fn cover(self, other: Span) -> Span {
         let start = cmp::min(self.start(), other.start());
         let end = cmp::max(self.end(), other.end());
-        Span::new(start, end)
+        Span::new(start, end).in_file(self.file_id)
     }
 }
 
@@ -95,6 +107,21 @@ impl SExpr {
             _ => None,
         }
     }
+
+    pub fn span(&self) -> Span {
+        match self {
+            SExpr::Atom(a) => a.span,
+            SExpr::List(l) => l.span,
+        }
+    }
+}
+
+/// Returns the span covering all of `exprs`, e.g. the contents of a top-level list.
+pub fn exprs_span(exprs: &[SExpr]) -> Span {
+    match (exprs.first(), exprs.last()) {
+        (Some(first), Some(last)) => first.span().cover(last.span()),
+        _ => Span::default(),
+    }
 }
 
 impl std::fmt::Debug for SExpr {
@@ -135,7 +162,7 @@ type TokenRes = Result<Token, String>;
 
 impl<'a> Lexer<'a> {
     #[allow(clippy::new_ret_no_self)]
-    fn new(s: &str) -> impl Iterator<Item = Spanned<TokenRes>> + '_ {
+    fn new(s: &str, file_id: usize) -> impl Iterator<Item = Spanned<TokenRes>> + '_ {
         let mut lexer = Lexer {
             s,
             bytes: s.bytes(),
@@ -143,7 +170,7 @@ impl<'a> Lexer<'a> {
         iter::from_fn(move || {
             lexer
                 .next_token()
-                .map(|(start, t)| Spanned::new(t, Span::new(start, lexer.pos())))
+                .map(|(start, t)| Spanned::new(t, Span::new(start, lexer.pos()).in_file(file_id)))
         })
     }
 
@@ -172,11 +199,20 @@ impl<'a> Lexer<'a> {
                         b'(' => Open,
                         b')' => Close,
                         b'"' => {
-                            self.next_while(|b| b != b'"' && b != b'\n');
-                            match self.bytes.next() {
-                                Some(b'"') => String,
-                                _ => return Some((start, Err("Unterminated string".to_string()))),
+                            if !self.next_quoted_string() {
+                                return Some((start, Err("Unterminated string".to_string())));
+                            }
+                            String
+                        }
+                        b'#' if self.bytes.clone().next() == Some(b'|') => {
+                            self.bytes.next();
+                            if !self.skip_block_comment() {
+                                return Some((
+                                    start,
+                                    Err("Unterminated block comment".to_string()),
+                                ));
                             }
+                            continue;
                         }
                         b';' => match self.bytes.clone().next() {
                             Some(b';') => {
@@ -199,6 +235,46 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Consume the rest of a string in double quotes, whose opening quote was already consumed.
+    /// Returns false if the string does not end on the same line.
+    fn next_quoted_string(&mut self) -> bool {
+        loop {
+            match self.bytes.next() {
+                Some(b'"') => return true,
+                // Skip the escaped character, so that an escaped quote does not end the string.
+                Some(b'\\') => match self.bytes.clone().next() {
+                    Some(b'\n') | None => return false,
+                    Some(_) => {
+                        self.bytes.next();
+                    }
+                },
+                Some(b'\n') | None => return false,
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Consume the rest of a `#| ... |#` block comment, whose opening `#|` was already consumed.
+    /// Block comments can be nested. Returns false if the comment does not end.
+    fn skip_block_comment(&mut self) -> bool {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.bytes.next() {
+                Some(b'#') if self.bytes.clone().next() == Some(b'|') => {
+                    self.bytes.next();
+                    depth += 1;
+                }
+                Some(b'|') if self.bytes.clone().next() == Some(b'#') => {
+                    self.bytes.next();
+                    depth -= 1;
+                }
+                Some(_) => {}
+                None => return false,
+            }
+        }
+        true
+    }
+
     fn next_string(&mut self) -> Token {
         // might want to limit this to ascii or XID_START/XID_CONTINUE
         self.next_while(|b| !is_start(b));
@@ -206,18 +282,47 @@ impl<'a> Lexer<'a> {
     }
 }
 
+/// Returns the text of the atom `s`. For a string in double quotes, this is the string without
+/// the quotes, in which `\"` and `\\` are replaced by the character they stand for. Other
+/// backslashes are kept, e.g. in Windows paths like `"C:\tools\new.exe"`.
+pub fn unquote(s: &str) -> String {
+    let s = match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(s) => s,
+        None => return s.to_owned(),
+    };
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(escaped @ ('"' | '\\'))) => {
+                chars.next();
+                res.push(escaped);
+            }
+            (c, _) => res.push(c),
+        }
+    }
+    res
+}
+
 type TopLevel = Spanned<Vec<SExpr>>;
 
-pub fn parse(s: &str) -> anyhow::Result<Vec<TopLevel>> {
-    parse_(s).map_err(|e| anyhow!(pretty_errors(s, vec![e])))
+/// Parse the text `s` of the file with the id `file_id`, which is stored in the spans.
+pub fn parse(s: &str, file_id: usize) -> anyhow::Result<Vec<TopLevel>> {
+    parse_(s, file_id).map_err(|e| {
+        anyhow::Error::from(super::error::SpannedError {
+            msg: e.t,
+            span: e.span,
+        })
+    })
 }
 
-fn parse_(s: &str) -> ParseResult<Vec<TopLevel>> {
-    parse_with(s, Lexer::new(s))
+fn parse_(s: &str, file_id: usize) -> ParseResult<Vec<TopLevel>> {
+    parse_with(s, file_id, Lexer::new(s, file_id))
 }
 
 fn parse_with(
     s: &str,
+    file_id: usize,
     mut tokens: impl Iterator<Item = Spanned<TokenRes>>,
 ) -> ParseResult<Vec<TopLevel>> {
     use SExpr::*;
@@ -254,7 +359,7 @@ fn parse_with(
     if !stack.is_empty() {
         return Err(Spanned::new(
             format!("{} Unclosed parentheses", stack.len()),
-            Span::new(s.len().saturating_sub(1), s.len()),
+            Span::new(s.len().saturating_sub(1), s.len()).in_file(file_id),
         ));
         // bail!("Unclosed parens");
     }
@@ -300,40 +405,111 @@ impl LineIndex {
             self.newlines
                 .get(line + 1)
                 .map(|off2| &s[off..off2 - 1])
-                .unwrap_or_else(|| &s[off..]),
+                .unwrap_or_else(|| &s[off..])
+                .trim_end_matches('\r'),
         )
     }
 }
 
-fn pretty_error(line_index: &LineIndex, s: &str, e: ParseError) -> String {
-    let line_col_start = line_index.line_col(e.span.start());
-    let line_col_end = line_index.line_col(e.span.end());
-    let padding = line_col_end.line.to_string().len();
-    let mut res = format!("error:\n{}\n", e.t);
-    for line_num in line_col_start.line..line_col_end.line {
-        let line = line_index.get_line(s, line_num as usize).unwrap();
-        let padding = " ".repeat(padding - line_num.to_string().len());
-        write!(res, "{line_num}{padding} | {line}").unwrap();
-    }
+/// Formats an error like:
+///
+/// ```text
+/// error: Unknown key in defsrc: "foo"
+///  --> kanata.kbd:3:8
+///   |
+/// 3 |   caps foo
+///   |        ^^^
+/// ```
+fn pretty_error(line_index: &LineIndex, file_name: &str, s: &str, e: ParseError) -> String {
+    let start = line_index.line_col(e.span.start());
+    let end = line_index.line_col(e.span.end());
+    let line = line_index
+        .get_line(s, start.line as usize)
+        .unwrap_or_default();
+    let start_col = cmp::min(start.col as usize, line.len());
+    // Spans covering several lines are underlined until the end of their first line.
+    let end_col = match end.line == start.line {
+        true => cmp::min(end.col as usize, line.len()),
+        false => line.len(),
+    };
+    let before = line.get(..start_col).unwrap_or_default();
+    let underlined = line.get(start_col..end_col).unwrap_or_default();
+    // Keep tabs so that the underline lines up with the snippet.
+    let indent: String = before
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let underline = "^".repeat(cmp::max(underlined.chars().count(), 1));
+    let line_num = (start.line + 1).to_string();
+    let padding = " ".repeat(line_num.len());
+    let mut res = format!("error: {}\n", e.t);
+    writeln!(
+        res,
+        "{padding}--> {file_name}:{}:{}",
+        line_num,
+        before.chars().count() + 1
+    )
+    .unwrap();
+    writeln!(res, "{padding} |").unwrap();
+    writeln!(res, "{line_num} | {line}").unwrap();
+    write!(res, "{padding} | {indent}{underline}").unwrap();
     res
 }
 
-pub fn pretty_errors(s: &str, mut errors: Vec<ParseError>) -> String {
+/// Formats errors pointing into the text `s` of the file `file_name`, sorted by their position.
+pub fn pretty_errors(file_name: &str, s: &str, mut errors: Vec<ParseError>) -> String {
     let line_index = LineIndex::new(s);
     errors.sort_by_key(|t| t.span);
     errors
         .into_iter()
-        .map(|e| format!("{}\n\n", pretty_error(&line_index, s, e)))
-        .collect()
+        .map(|e| pretty_error(&line_index, file_name, s, e))
+        .collect::<Vec<_>>()
+        .join("\n\n")
 }
 
 #[test]
 fn span_works() {
     let s = "(hello world my oyster)\n(row two)";
-    let tlevel = parse(s).unwrap();
+    let tlevel = parse(s, 0).unwrap();
     assert_eq!(
         &s[tlevel[0].span.start..tlevel[0].span.end],
         "(hello world my oyster)"
     );
     assert_eq!(&s[tlevel[1].span.start..tlevel[1].span.end], "(row two)");
 }
+
+#[test]
+fn pretty_error_points_at_span() {
+    let s = "(a b)\n(\tcd efg)\n";
+    let start = s.find("efg").unwrap();
+    let e = Spanned::new("bad".to_string(), Span::new(start, start + 3));
+    assert_eq!(
+        pretty_errors("x.kbd", s, vec![e]),
+        "error: bad\n --> x.kbd:2:6\n  |\n2 | (\tcd efg)\n  |  \t   ^^^"
+    );
+}
+
+#[test]
+fn quoted_strings_and_block_comments() {
+    let s = "(a \"b c\" \"d\\\"(e)\\\\\" #| (skipped) #| nested |# |# f)";
+    let tlevel = parse(s, 0).unwrap();
+    let atoms: Vec<_> = tlevel[0].t.iter().map(|e| e.atom().unwrap()).collect();
+    assert_eq!(atoms, vec!["a", "\"b c\"", "\"d\\\"(e)\\\\\"", "f"]);
+    assert_eq!(&s[tlevel[0].t[3].span()], "f");
+    assert_eq!(unquote(atoms[1]), "b c");
+    assert_eq!(unquote(atoms[2]), "d\"(e)\\");
+    assert_eq!(unquote("\"C:\\tools\\new.exe\""), "C:\\tools\\new.exe");
+    assert_eq!(unquote("\"C:\\Program Files\\\\\""), "C:\\Program Files\\");
+    assert_eq!(unquote("a"), "a");
+
+    for (s, msg, start) in [
+        ("(a \"b\nc\")", "Unterminated string", 3),
+        ("(a \"b\\\")", "Unterminated string", 3),
+        ("(a)\n#| (b)\n", "Unterminated block comment", 4),
+    ] {
+        match parse_(s, 0) {
+            Err(e) => assert_eq!((e.t.as_str(), e.span.start()), (msg, start)),
+            Ok(_) => panic!("expected {msg} for {s:?}"),
+        }
+    }
+}
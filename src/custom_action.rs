@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CustomAction {
     Cmd(&'static [String]),
@@ -20,7 +22,15 @@ pub enum CustomAction {
         distance: u16,
     },
     SequenceLeader,
+    CapsWord {
+        /// Milliseconds after the last key of the word until caps-word turns off.
+        timeout: u16,
+        /// Whether `-` is shifted to `_` as well.
+        shift_minus: bool,
+    },
     LiveReload,
+    /// Live reload into the configuration with the given name, see `Kanata::request_switch_config`.
+    SwitchConfig(&'static str),
     Repeat,
     CancelMacroOnRelease,
 }
@@ -40,7 +50,7 @@ pub struct Coord {
     pub y: u16,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FakeKeyAction {
     Press,
     Release,
@@ -15,9 +15,14 @@ impl Kanata {
         {
             let mut mapped_keys = MAPPED_KEYS.lock();
             *mapped_keys = kanata.lock().mapped_keys.clone();
+            *DEVICE_MAPPED_KEYS.lock() = kanata.lock().device_mapped_keys();
         }
 
-        let mut kbd_in = match KbdIn::new(&kanata.lock().kbd_in_paths) {
+        let kbd_in = {
+            let kanata = kanata.lock();
+            KbdIn::new(&kanata.kbd_in_paths, &kanata.devices)
+        };
+        let mut kbd_in = match kbd_in {
             Ok(kbd_in) => kbd_in,
             Err(e) => {
                 bail!("failed to open keyboard device: {}", e)
@@ -29,9 +34,9 @@ impl Kanata {
             log::trace!("{events:?}");
 
             // Pass-through non-key events
-            for in_event in events.into_iter() {
+            for (in_event, device) in events.into_iter() {
                 let key_event = match KeyEvent::try_from(in_event) {
-                    Ok(ev) => ev,
+                    Ok(ev) => KeyEvent { device, ..ev },
                     _ => {
                         let mut kanata = kanata.lock();
                         kanata
@@ -45,7 +50,14 @@ impl Kanata {
                 // Check if this keycode is mapped in the configuration. If it hasn't been mapped, send
                 // it immediately.
                 check_for_exit(&key_event);
-                if !MAPPED_KEYS.lock().contains(&key_event.code) {
+                let is_mapped = match key_event.device {
+                    Some(device) => DEVICE_MAPPED_KEYS
+                        .lock()
+                        .get(usize::from(device))
+                        .is_some_and(|keys| keys.contains(&key_event.code)),
+                    None => MAPPED_KEYS.lock().contains(&key_event.code),
+                };
+                if !is_mapped {
                     let mut kanata = kanata.lock();
                     kanata
                         .kbd_out
@@ -4,9 +4,9 @@ use anyhow::{anyhow, bail, Result};
 use log::{error, info};
 
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::net::TcpStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
 use std::time;
 
@@ -16,23 +16,30 @@ use std::sync::Arc;
 use crate::cfg::LayerInfo;
 use crate::custom_action::*;
 use crate::keys::*;
+use crate::layers::DEVICE_ROWS_START;
 use crate::oskbd::*;
-use crate::tcp_server::ServerMessage;
+use crate::tcp_server::{Connections, ServerMessage};
 use crate::{cfg, ValidatedArgs};
 
 use kanata_keyberon::key_code::*;
 use kanata_keyberon::layout::*;
 
-type HashSet<T> = rustc_hash::FxHashSet<T>;
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+type HashSet<T> = rustc_hash::FxHashSet<T>;
 
 pub struct Kanata {
     pub kbd_in_paths: Vec<String>,
-    pub kbd_out: KbdOut,
+    pub kbd_out: Box<dyn KeyOutput>,
     pub cfg_path: PathBuf,
+    /// All configurations given to `--cfg`, which `switch-config` can switch between.
+    pub cfg_paths: Vec<PathBuf>,
+    /// The configuration to switch to on the next live reload.
+    next_cfg_path: Option<PathBuf>,
+    /// The configuration file and the files that it includes, as of the last successful load.
+    pub cfg_files: Vec<PathBuf>,
     pub mapped_keys: cfg::MappedKeys,
     pub key_outputs: cfg::KeyOutputs,
-    pub layout: cfg::KanataLayout,
+    pub layout: cfg::Allocated<cfg::KanataLayout>,
     pub prev_keys: Vec<KeyCode>,
     pub layer_info: Vec<LayerInfo>,
     pub prev_layer: usize,
@@ -40,8 +47,29 @@ pub struct Kanata {
     pub hscroll_state: Option<ScrollState>,
     pub sequence_timeout: u16,
     pub sequence_state: Option<SequenceState>,
+    pub caps_word_state: Option<CapsWordState>,
+    /// Keys that caps-word pressed with shift and that are still held. Shift stays pressed until
+    /// the last of them is released, so that autorepeat keeps typing capitals.
+    caps_word_shifted: Vec<KeyCode>,
     pub sequences: cfg::KeySeqsToFKeys,
+    pub chord_groups: cfg::Allocated<cfg::ChordGroups>,
+    pub chord_state: Option<ChordState>,
+    pub devices: Vec<cfg::DeviceCfg>,
+    pub fake_keys: cfg::FakeKeys,
+    /// Text of the aliases and sequences, used to log what a live reload changed.
+    pub def_texts: cfg::DefTexts,
+    /// Notifications produced since the last tick, sent to the TCP server at the end of it.
+    notifications: Vec<ServerMessage>,
+    prev_oneshot_active: bool,
+    /// Set when a live reload was requested but keys are still pressed.
+    live_reload_requested: bool,
+    /// Senders for the result of the requested live reload, for clients waiting on it.
+    reload_result_txs: Vec<Sender<Result<(), String>>>,
     last_tick: time::Instant,
+    /// Used to wake up a blocked processing loop when the layout state was changed from outside
+    /// of it, e.g. by a TCP client.
+    wake_tx: Sender<()>,
+    wake_rx: Receiver<()>,
     #[cfg(all(feature = "interception_driver", target_os = "windows"))]
     kbd_out_rx: Receiver<InputEvent>,
 }
@@ -58,6 +86,19 @@ pub struct SequenceState {
     pub ticks_until_timeout: u16,
 }
 
+pub struct CapsWordState {
+    pub timeout: u16,
+    pub ticks_until_timeout: u16,
+    pub shift_minus: bool,
+}
+
+/// Key events for a chord group that are held back until it is known whether they form a chord.
+pub struct ChordState {
+    pub group: usize,
+    pub events: heapless::Vec<Event, 8>,
+    pub ticks_until_flush: u16,
+}
+
 static LAST_PRESSED_KEY: AtomicU32 = AtomicU32::new(0);
 
 const SEQUENCE_TIMEOUT_ERR: &str = "sequence-timeout should be a number (1-65535)";
@@ -67,6 +108,8 @@ use once_cell::sync::Lazy;
 
 static MAPPED_KEYS: Lazy<Mutex<cfg::MappedKeys>> =
     Lazy::new(|| Mutex::new(cfg::MappedKeys::default()));
+/// Mapped keys of each `defdevice` configuration, by index.
+static DEVICE_MAPPED_KEYS: Lazy<Mutex<Vec<cfg::MappedKeys>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -75,30 +118,63 @@ pub use windows::*;
 
 #[cfg(target_os = "linux")]
 mod linux;
-#[cfg(target_os = "linux")]
-pub use linux::*;
+
+mod simulate;
 
 impl Kanata {
     /// Create a new configuration from a file.
     pub fn new(args: &ValidatedArgs) -> Result<Self> {
-        let cfg = cfg::Cfg::new_from_file(&args.path)?;
+        let cfg = cfg::Cfg::new_from_file(&args.paths[0])?;
+        check_cfg(&cfg, &args.paths)?;
 
         #[cfg(all(feature = "interception_driver", target_os = "windows"))]
         let (kbd_out_tx, kbd_out_rx) = crossbeam_channel::unbounded();
 
-        let kbd_out = match KbdOut::new(
-            #[cfg(target_os = "linux")]
-            &args.symlink_path,
-            #[cfg(all(feature = "interception_driver", target_os = "windows"))]
-            kbd_out_tx,
-        ) {
-            Ok(kbd_out) => kbd_out,
-            Err(err) => {
-                error!("Failed to open the output uinput device. Make sure you've added kanata to the `uinput` group");
-                bail!(err)
+        let kbd_out: Box<dyn KeyOutput> = match &args.output {
+            OutputBackend::Os => match KbdOut::new(
+                #[cfg(target_os = "linux")]
+                &args.symlink_path,
+                #[cfg(all(feature = "interception_driver", target_os = "windows"))]
+                kbd_out_tx,
+            ) {
+                Ok(kbd_out) => Box::new(kbd_out),
+                Err(err) => {
+                    error!("Failed to open the output uinput device. Make sure you've added kanata to the `uinput` group");
+                    bail!(err)
+                }
+            },
+            OutputBackend::Log => Box::new(LoggingOutput),
+            OutputBackend::Tcp(addr) => match TcpForwardOutput::connect(addr) {
+                Ok(kbd_out) => Box::new(kbd_out),
+                Err(err) => bail!("failed to connect to output address {addr}: {err}"),
+            },
+        };
+
+        #[cfg(target_os = "windows")]
+        unsafe {
+            log::info!("Asking Windows to improve timer precision");
+            if winapi::um::timeapi::timeBeginPeriod(1) == winapi::um::mmsystem::TIMERR_NOCANDO {
+                bail!("failed to improve timer precision");
             }
+        }
+
+        let kanata = Self::new_with_output(args.paths[0].clone(), cfg, kbd_out)?;
+        let kanata = Self {
+            cfg_paths: args.paths.clone(),
+            #[cfg(all(feature = "interception_driver", target_os = "windows"))]
+            kbd_out_rx,
+            ..kanata
         };
+        Ok(kanata)
+    }
 
+    /// Create a new instance from a parsed configuration that sends its output events to
+    /// `kbd_out` instead of opening an OS output device.
+    pub fn new_with_output(
+        cfg_path: PathBuf,
+        cfg: cfg::Cfg,
+        kbd_out: Box<dyn KeyOutput>,
+    ) -> Result<Self> {
         #[cfg(target_os = "linux")]
         let kbd_in_paths = cfg
             .items
@@ -109,33 +185,24 @@ impl Kanata {
         #[cfg(not(target_os = "linux"))]
         let kbd_in_paths = vec![];
 
-        #[cfg(target_os = "windows")]
-        unsafe {
-            log::info!("Asking Windows to improve timer precision");
-            if winapi::um::timeapi::timeBeginPeriod(1) == winapi::um::mmsystem::TIMERR_NOCANDO {
-                bail!("failed to improve timer precision");
-            }
+        set_altgr_behaviour(&cfg)?;
+
+        #[cfg(not(target_os = "linux"))]
+        if !cfg.devices.is_empty() {
+            log::warn!("defdevice is only supported on Linux and will be ignored");
         }
 
-        set_altgr_behaviour(&cfg)?;
+        let sequence_timeout = parse_sequence_timeout(&cfg)?;
 
-        let sequence_timeout = cfg
-            .items
-            .get("sequence-timeout")
-            .map(|s| str::parse::<u16>(s))
-            .transpose()
-            .map_err(|e| anyhow!("{SEQUENCE_TIMEOUT_ERR}: {e:?}"))?
-            .map(|i| match i {
-                0 => Err(anyhow!("{SEQUENCE_TIMEOUT_ERR}")),
-                _ => Ok(i),
-            })
-            .transpose()?
-            .unwrap_or(SEQUENCE_TIMEOUT_DEFAULT);
+        let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
 
         Ok(Self {
             kbd_in_paths,
             kbd_out,
-            cfg_path: args.path.clone(),
+            cfg_paths: vec![cfg_path.clone()],
+            cfg_path,
+            next_cfg_path: None,
+            cfg_files: cfg.files,
             mapped_keys: cfg.mapped_keys,
             key_outputs: cfg.key_outputs,
             layout: cfg.layout,
@@ -146,10 +213,23 @@ impl Kanata {
             hscroll_state: None,
             sequence_timeout,
             sequence_state: None,
+            caps_word_state: None,
+            caps_word_shifted: vec![],
             sequences: cfg.sequences,
+            chord_groups: cfg.chord_groups,
+            chord_state: None,
+            devices: cfg.devices,
+            fake_keys: cfg.fake_keys,
+            def_texts: cfg.def_texts,
+            notifications: vec![],
+            prev_oneshot_active: false,
+            live_reload_requested: false,
+            reload_result_txs: vec![],
             last_tick: time::Instant::now(),
+            wake_tx,
+            wake_rx,
             #[cfg(all(feature = "interception_driver", target_os = "windows"))]
-            kbd_out_rx,
+            kbd_out_rx: crossbeam_channel::never(),
         })
     }
 
@@ -161,50 +241,150 @@ impl Kanata {
     /// Update keyberon layout state for press/release, handle repeat separately
     fn handle_key_event(&mut self, event: &KeyEvent) -> Result<()> {
         let evc: u32 = event.code.into();
+        let row = match self.device_idx(event) {
+            Some(device) => (DEVICE_ROWS_START + device) as u8,
+            None => 0,
+        };
         let kbrn_ev = match event.value {
-            KeyValue::Press => Event::Press(0, evc as u16),
-            KeyValue::Release => Event::Release(0, evc as u16),
+            KeyValue::Press => Event::Press(row, evc as u16),
+            KeyValue::Release => Event::Release(row, evc as u16),
             KeyValue::Repeat => return self.handle_repeat(event),
         };
-        self.layout.event(kbrn_ev);
+        self.handle_chord_event(kbrn_ev);
         Ok(())
     }
 
+    /// Returns the index of the `defdevice` configuration that applies to the event, if it is
+    /// still part of the configuration.
+    fn device_idx(&self, event: &KeyEvent) -> Option<usize> {
+        event
+            .device
+            .map(usize::from)
+            .filter(|&device| device < self.devices.len())
+    }
+
+    /// Mapped keys of each `defdevice` configuration, by index.
+    fn device_mapped_keys(&self) -> Vec<cfg::MappedKeys> {
+        self.devices.iter().map(|d| d.mapped_keys.clone()).collect()
+    }
+
+    /// Send an event to the layout, holding it back first if the key is part of a chord group.
+    /// Chords only apply to keys of the top-level `defsrc`.
+    fn handle_chord_event(&mut self, event: Event) {
+        let (row, osc) = event.coord();
+        let group = match self
+            .chord_groups
+            .iter()
+            .position(|g| row == 0 && g.keys.contains(&osc))
+        {
+            Some(group) => group,
+            None => {
+                // Keep the order of events intact by sending any held back events first.
+                self.flush_chord_state();
+                self.layout.event(event);
+                return;
+            }
+        };
+        if matches!(&self.chord_state, Some(state) if state.group != group) {
+            self.flush_chord_state();
+        }
+        let timeout = self.chord_groups[group].timeout;
+        let state = self.chord_state.get_or_insert_with(|| ChordState {
+            group,
+            events: heapless::Vec::new(),
+            ticks_until_flush: timeout,
+        });
+        // Cannot fail: the state is flushed below as soon as it is full.
+        let _ = state.events.push(event);
+        // A release ends the waiting period since it means the keys are not being held together.
+        if event.is_release()
+            || state.events.is_full()
+            || self.chord_groups[group].is_resolved(&state.events)
+        {
+            self.flush_chord_state();
+        }
+    }
+
+    /// Run the held back events through the chording engine and send the results to the layout.
+    fn flush_chord_state(&mut self) {
+        if let Some(state) = self.chord_state.take() {
+            let group = &mut self.chord_groups[state.group];
+            log::debug!("chord group {}: processing {:?}", group.name, state.events);
+            for event in group.chording.tick(state.events) {
+                self.layout.event(event);
+            }
+        }
+    }
+
+    fn tick_chord_state(&mut self) {
+        if let Some(state) = &mut self.chord_state {
+            state.ticks_until_flush = state.ticks_until_flush.saturating_sub(1);
+            if state.ticks_until_flush == 0 {
+                log::debug!("chord timeout");
+                self.flush_chord_state();
+            }
+        }
+    }
+
     /// Advance keyberon layout state and send events based on changes to its state.
     fn handle_time_ticks(&mut self, tx: &Option<Sender<ServerMessage>>) -> Result<()> {
         let now = time::Instant::now();
         let ms_elapsed = now.duration_since(self.last_tick).as_millis();
+        if ms_elapsed > 0 {
+            self.last_tick = now;
+        }
+        self.tick_ms(ms_elapsed, tx)
+    }
 
-        let mut live_reload_requested = false;
-
+    /// Advance keyberon layout state by `ms_elapsed` milliseconds and send events based on changes
+    /// to its state.
+    pub fn tick_ms(&mut self, ms_elapsed: u128, tx: &Option<Sender<ServerMessage>>) -> Result<()> {
         for _ in 0..ms_elapsed {
+            self.tick_chord_state();
             let custom_event = self.layout.tick();
             let cur_keys = self.handle_keystate_changes()?;
-            live_reload_requested |= self.handle_custom_event(custom_event)?;
+            self.live_reload_requested |= self.handle_custom_event(custom_event)?;
             self.handle_scrolling()?;
             self.tick_sequence_state();
+            self.tick_caps_word_state();
 
-            if live_reload_requested && self.prev_keys.is_empty() && cur_keys.is_empty() {
-                live_reload_requested = false;
+            if self.live_reload_requested && self.prev_keys.is_empty() && cur_keys.is_empty() {
+                self.live_reload_requested = false;
                 if let Err(e) = self.do_live_reload() {
                     log::error!("live reload failed {e}");
                 }
             }
+            self.check_oneshot_change();
 
             self.prev_keys = cur_keys;
         }
 
         if ms_elapsed > 0 {
-            self.last_tick = now;
-
             // Handle layer change outside the loop. I don't see any practical scenario where it
             // would make a difference, so may as well reduce the amount of processing.
             self.check_handle_layer_change(tx);
         }
 
+        let notifications = std::mem::take(&mut self.notifications);
+        if let Some(tx) = tx {
+            for notification in notifications {
+                if let Err(error) = tx.try_send(notification) {
+                    log::error!("could not send event notification: {}", error);
+                }
+            }
+        }
+
         Ok(())
     }
 
+    fn check_oneshot_change(&mut self) {
+        let active = !self.layout.oneshot.keys.is_empty();
+        if active != self.prev_oneshot_active {
+            self.prev_oneshot_active = active;
+            self.notifications.push(ServerMessage::OneShot { active });
+        }
+    }
+
     /// Returns true if live reload is requested and false otherwise.
     fn handle_custom_event(
         &mut self,
@@ -219,11 +399,19 @@ impl Kanata {
                     match custact {
                         // For unicode, only send on the press. No repeat action is supported for this for
                         // now.
-                        CustomAction::Unicode(c) => self.kbd_out.send_unicode(*c)?,
+                        CustomAction::Unicode(c) => self.send_unicode(*c)?,
                         CustomAction::LiveReload => {
                             live_reload_requested = true;
                             log::info!("Requested live reload")
                         }
+                        CustomAction::SwitchConfig(name) => match self.cfg_path_by_name(name) {
+                            Ok(path) => {
+                                self.next_cfg_path = Some(path);
+                                live_reload_requested = true;
+                                log::info!("Requested switch to configuration {name}")
+                            }
+                            Err(e) => log::error!("{e}"),
+                        },
                         CustomAction::Mouse(btn) => {
                             log::debug!("click     {:?}", btn);
                             if let Some(pbtn) = prev_mouse_btn {
@@ -262,7 +450,8 @@ impl Kanata {
                             }
                         },
                         CustomAction::Cmd(cmd) => {
-                            cmds.push(*cmd);
+                            // The command may outlive the configuration if it is reloaded.
+                            cmds.push(cmd.to_vec());
                         }
                         CustomAction::FakeKey { coord, action } => {
                             let (x, y) = (coord.x, coord.y);
@@ -280,8 +469,24 @@ impl Kanata {
                             log::debug!("on-press: sleeping for {delay} ms");
                             std::thread::sleep(std::time::Duration::from_millis((*delay).into()));
                         }
+                        CustomAction::CapsWord {
+                            timeout,
+                            shift_minus,
+                        } => {
+                            log::debug!("entering caps-word");
+                            if self.caps_word_state.is_none() {
+                                self.notifications
+                                    .push(ServerMessage::CapsWord { active: true });
+                            }
+                            self.caps_word_state = Some(CapsWordState {
+                                timeout: *timeout,
+                                ticks_until_timeout: *timeout,
+                                shift_minus: *shift_minus,
+                            });
+                        }
                         CustomAction::SequenceLeader => {
                             log::debug!("entering sequence mode");
+                            self.notifications.push(ServerMessage::SequenceEnter {});
                             self.sequence_state = Some(SequenceState {
                                 sequence: vec![],
                                 ticks_until_timeout: self.sequence_timeout,
@@ -291,9 +496,9 @@ impl Kanata {
                             let key = OsCode::from(LAST_PRESSED_KEY.load(SeqCst));
                             log::debug!("repeating a keypress {key:?}");
                             // Release key in case the most recently pressed key is still pressed.
-                            self.kbd_out.release_key(key)?;
-                            self.kbd_out.press_key(key)?;
-                            self.kbd_out.release_key(key)?;
+                            self.release_key(key)?;
+                            self.press_key(key)?;
+                            self.release_key(key)?;
                         }
                         _ => {}
                     }
@@ -394,22 +599,113 @@ impl Kanata {
             if state.ticks_until_timeout == 0 {
                 log::debug!("sequence timeout; exiting sequence state");
                 self.sequence_state = None;
+                self.notifications.push(ServerMessage::SequenceExit {});
+            }
+        }
+    }
+
+    fn tick_caps_word_state(&mut self) {
+        if let Some(state) = &mut self.caps_word_state {
+            state.ticks_until_timeout -= 1;
+            if state.ticks_until_timeout == 0 {
+                log::debug!("caps-word timeout; exiting caps-word");
+                self.end_caps_word();
             }
         }
     }
 
+    fn end_caps_word(&mut self) {
+        self.caps_word_state = None;
+        self.notifications
+            .push(ServerMessage::CapsWord { active: false });
+    }
+
+    /// Returns whether caps-word shifts the newly pressed key `osc`. Keys that are not part of a
+    /// word end caps-word, and the others restart its timeout.
+    fn caps_word_shifts(&mut self, osc: OsCode) -> bool {
+        let Some(state) = &mut self.caps_word_state else {
+            return false;
+        };
+        let shift = osc.is_letter() || (state.shift_minus && osc == OsCode::KEY_MINUS);
+        let continues_word = osc.is_digit()
+            || matches!(
+                osc,
+                OsCode::KEY_MINUS
+                    | OsCode::KEY_BACKSPACE
+                    | OsCode::KEY_DELETE
+                    | OsCode::KEY_LEFTSHIFT
+                    | OsCode::KEY_RIGHTSHIFT
+            );
+        if shift || continues_word {
+            state.ticks_until_timeout = state.timeout;
+        } else {
+            log::debug!("{osc:?} is not part of a word; exiting caps-word");
+            self.end_caps_word();
+        }
+        shift
+    }
+
+    fn press_key(&mut self, osc: OsCode) -> Result<()> {
+        log::debug!("key press     {:?}", osc);
+        if let Err(e) = self.kbd_out.press_key(osc) {
+            bail!("failed to press key: {:?}", e);
+        }
+        self.notifications.push(ServerMessage::KeyOutput {
+            key: format!("{:?}", osc),
+            pressed: true,
+        });
+        Ok(())
+    }
+
+    fn release_key(&mut self, osc: OsCode) -> Result<()> {
+        log::debug!("key release   {:?}", osc);
+        if let Err(e) = self.kbd_out.release_key(osc) {
+            bail!("failed to release key: {:?}", e);
+        }
+        self.notifications.push(ServerMessage::KeyOutput {
+            key: format!("{:?}", osc),
+            pressed: false,
+        });
+        Ok(())
+    }
+
+    fn repeat_key(&mut self, osc: OsCode) -> Result<()> {
+        log::debug!("repeat    {:?}", KeyCode::from(osc));
+        if let Err(e) = self.kbd_out.write_key(osc, KeyValue::Repeat) {
+            bail!("could not write key {:?}", e)
+        }
+        self.notifications.push(ServerMessage::KeyRepeat {
+            key: format!("{:?}", osc),
+        });
+        Ok(())
+    }
+
+    fn send_unicode(&mut self, c: char) -> Result<()> {
+        log::debug!("unicode       {c}");
+        if let Err(e) = self.kbd_out.send_unicode(c) {
+            bail!("failed to send unicode: {:?}", e);
+        }
+        self.notifications.push(ServerMessage::UnicodeOutput {
+            text: c.to_string(),
+        });
+        Ok(())
+    }
+
     fn release_with_keyberon_output(&mut self, cur_keys: &[KeyCode]) -> Result<()> {
         // Release keys that are missing from the current state but exist in the previous
         // state. It's important to iterate using a Vec because the order matters. This used to
         // use HashSet for computing `difference` but that iteration order is random which is
         // not what we want.
-        for k in &self.prev_keys {
-            if cur_keys.contains(k) {
-                continue;
-            }
-            log::debug!("key release   {:?}", k);
-            if let Err(e) = self.kbd_out.release_key(k.into()) {
-                bail!("failed to release key: {:?}", e);
+        for i in 0..self.prev_keys.len() {
+            let k = self.prev_keys[i];
+            if !cur_keys.contains(&k) {
+                self.release_key(k.into())?;
+                if let Some(i) = self.caps_word_shifted.iter().position(|s| *s == k) {
+                    self.caps_word_shifted.remove(i);
+                    if self.caps_word_shifted.is_empty() {
+                        self.release_key(OsCode::KEY_LEFTSHIFT)?;
+                    }
+                }
             }
         }
         Ok(())
@@ -432,10 +728,22 @@ impl Kanata {
             LAST_PRESSED_KEY.store(OsCode::from(k).into(), SeqCst);
             match &mut self.sequence_state {
                 None => {
-                    log::debug!("key press     {:?}", k);
-                    if let Err(e) = self.kbd_out.press_key(k.into()) {
-                        bail!("failed to press key: {:?}", e);
+                    // Caps-word shifts only the keys of the word, and not if shift is already held.
+                    let shift = self.caps_word_shifts(k.into())
+                        && !cur_keys
+                            .iter()
+                            .any(|k| matches!(k, KeyCode::LShift | KeyCode::RShift));
+                    if shift {
+                        if self.caps_word_shifted.is_empty() {
+                            self.press_key(OsCode::KEY_LEFTSHIFT)?;
+                        }
+                        self.caps_word_shifted.push(*k);
+                    } else if !self.caps_word_shifted.is_empty() {
+                        // The shifted keys that are still held are typed in lowercase from now on.
+                        self.caps_word_shifted.clear();
+                        self.release_key(OsCode::KEY_LEFTSHIFT)?;
                     }
+                    self.press_key(k.into())?;
                 }
                 Some(state) => {
                     state.ticks_until_timeout = self.sequence_timeout;
@@ -455,11 +763,13 @@ impl Kanata {
                             });
                         }
                         self.sequence_state = None;
+                        self.notifications.push(ServerMessage::SequenceComplete {});
                         self.layout.event(Event::Press(*x, *y));
                         self.layout.event(Event::Release(*x, *y));
                     } else if self.sequences.get_raw_descendant(&state.sequence).is_none() {
                         log::debug!("got invalid sequence; exiting sequence mode");
                         self.sequence_state = None;
+                        self.notifications.push(ServerMessage::SequenceExit {});
                     }
                 }
             }
@@ -467,27 +777,100 @@ impl Kanata {
         Ok(cur_keys)
     }
 
-    fn do_live_reload(&mut self) -> Result<()> {
-        let cfg = cfg::Cfg::new_from_file(&self.cfg_path)?;
+    /// Reload the configuration file, or switch to the configuration requested by
+    /// `switch-config`, keeping the current configuration if it fails to load.
+    pub fn do_live_reload(&mut self) -> Result<()> {
+        let cfg_path = self
+            .next_cfg_path
+            .take()
+            .unwrap_or_else(|| self.cfg_path.clone());
+        let res = self.reload_cfg(&cfg_path);
+        if res.is_ok() && cfg_path != self.cfg_path {
+            log::info!("Switched to configuration {}", cfg_name(&cfg_path));
+            self.cfg_path = cfg_path;
+        }
+        self.notifications.push(match &res {
+            Ok(()) => ServerMessage::ReloadSuccess {},
+            Err(e) => ServerMessage::ReloadFailure { msg: e.to_string() },
+        });
+        for tx in self.reload_result_txs.drain(..) {
+            let _ = tx.send(res.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        }
+        // Let the processing loop send the notification if it is blocked.
+        self.wake();
+        res
+    }
+
+    fn reload_cfg(&mut self, cfg_path: &Path) -> Result<()> {
+        let cfg = cfg::Cfg::new_from_file(cfg_path)?;
+        check_cfg(&cfg, &self.cfg_paths)?;
         set_altgr_behaviour(&cfg).map_err(|e| anyhow!("failed to set altgr behaviour {e})"))?;
-        self.sequence_timeout = cfg
-            .items
-            .get("sequence-timeout")
-            .map(|s| str::parse::<u16>(s))
-            .transpose()
-            .map_err(|e| anyhow!("{SEQUENCE_TIMEOUT_ERR}: {e:?}"))?
-            .map(|i| match i {
-                0 => Err(anyhow!("{SEQUENCE_TIMEOUT_ERR}")),
-                _ => Ok(i),
-            })
-            .transpose()?
-            .unwrap_or(SEQUENCE_TIMEOUT_DEFAULT);
+        let sequence_timeout = parse_sequence_timeout(&cfg)?;
+        log_reload_changes(
+            "layer",
+            &layer_texts(&self.layer_info),
+            &layer_texts(&cfg.layer_info),
+        );
+        log_reload_changes("alias", &self.def_texts.aliases, &cfg.def_texts.aliases);
+        log_reload_changes(
+            "sequence",
+            &self.def_texts.sequences,
+            &cfg.def_texts.sequences,
+        );
+        log_reload_changes(
+            "variable",
+            &self.def_texts.variables,
+            &cfg.def_texts.variables,
+        );
+
+        // Keep the default and active layers if they still exist. The active layer is missing if
+        // it was removed by a previous reload and no tick has happened since.
+        let layer_idx = |idx: usize| {
+            let name = &self.layer_info.get(idx)?.name;
+            cfg.layer_info.iter().position(|l| &l.name == name)
+        };
+        let default_layer = layer_idx(self.layout.default_layer);
+        let prev_layer = layer_idx(self.prev_layer);
+
+        // Keys that are still pressed are released because the new layout does not know about
+        // them. This is the last step that can fail, so that a failed reload keeps the previous
+        // configuration intact.
+        self.release_with_keyberon_output(&[])?;
+        self.prev_keys.clear();
+
+        // The layout and chord groups are replaced along with the allocations they reference.
+        self.chord_state = None;
         self.layout = cfg.layout;
+        self.chord_groups = cfg.chord_groups;
+        if let Some(default_layer) = default_layer {
+            self.layout.set_default_layer(default_layer);
+        }
+        // An active layer that no longer exists is reported as a layer change on the next tick.
+        self.prev_layer = prev_layer.unwrap_or(usize::MAX);
+        self.sequence_timeout = sequence_timeout;
+        self.scroll_state = None;
+        self.hscroll_state = None;
+
         let mut mapped_keys = MAPPED_KEYS.lock();
-        *mapped_keys = cfg.mapped_keys;
+        *mapped_keys = cfg.mapped_keys.clone();
+        self.mapped_keys = cfg.mapped_keys;
         self.key_outputs = cfg.key_outputs;
         self.layer_info = cfg.layer_info;
+        self.def_texts = cfg.def_texts;
+        self.cfg_files = cfg.files;
         self.sequences = cfg.sequences;
+        self.fake_keys = cfg.fake_keys;
+        let matchers_changed = self.devices.len() != cfg.devices.len()
+            || self
+                .devices
+                .iter()
+                .zip(cfg.devices.iter())
+                .any(|(old, new)| old.matchers != new.matchers);
+        if matchers_changed {
+            log::warn!("defdevice matching changed: restart kanata for devices to be rematched");
+        }
+        self.devices = cfg.devices;
+        *DEVICE_MAPPED_KEYS.lock() = self.device_mapped_keys();
         log::info!("Live reload successful");
         Ok(())
     }
@@ -503,53 +886,111 @@ impl Kanata {
         }
         let active_keycodes: HashSet<KeyCode> = self.layout.keycodes().collect();
         let current_layer = self.layout.current_layer();
-        if current_layer % 2 == 1 {
-            // Prioritize checking the active layer in case a layer-while-held is active.
-            if let Some(outputs_for_key) = self.key_outputs[current_layer].get(&event.code) {
-                log::debug!("key outs for active layer-while-held: {outputs_for_key:?};");
-                for kc in outputs_for_key.iter().rev() {
-                    if active_keycodes.contains(&kc.into()) {
-                        log::debug!("repeat    {:?}", KeyCode::from(*kc));
-                        if let Err(e) = self.kbd_out.write_key(*kc, KeyValue::Repeat) {
-                            bail!("could not write key {:?}", e)
-                        }
-                        return Ok(());
-                    }
-                }
-            } else {
-                log::debug!("empty layer-while-held outputs, probably transparent");
-            }
-        }
-        // Try matching a key on the default layer.
-        //
-        // This code executes in two cases:
-        // 1. current layer is the default layer
-        // 2. current layer is layer-while-held but did not find a match in the code above, e.g. a
-        //    transparent key was pressed.
-        let outputs_for_key = match self.key_outputs[self.layout.default_layer].get(&event.code) {
-            None => return Ok(()),
-            Some(v) => v,
+        let key_outputs = match self.device_idx(event) {
+            Some(device) => &self.devices[device].key_outputs,
+            None => &self.key_outputs,
         };
-        log::debug!("key outs for default layer: {outputs_for_key:?};");
-        for kc in outputs_for_key.iter().rev() {
-            if active_keycodes.contains(&kc.into()) {
-                log::debug!("repeat    {:?}", KeyCode::from(*kc));
-                if let Err(e) = self.kbd_out.write_key(*kc, KeyValue::Repeat) {
-                    bail!("could not write key {:?}", e)
+        // Check the active layer first in case a layer-while-held is active, then the default
+        // layer for transparent keys, then the last layer for transparent keys on the default
+        // layer, which holds the actions of defsrc.
+        let mut layers = vec![current_layer];
+        if self.layout.default_layer != current_layer {
+            layers.push(self.layout.default_layer);
+        }
+        layers.push(key_outputs.len() - 1);
+        for layer in layers {
+            let outputs_for_key = match key_outputs[layer].get(&event.code) {
+                None => {
+                    log::debug!("empty outputs for layer {layer}, probably transparent");
+                    continue;
+                }
+                Some(v) => v,
+            };
+            log::debug!("key outs for layer {layer}: {outputs_for_key:?};");
+            for kc in outputs_for_key.iter().rev() {
+                if active_keycodes.contains(&kc.into()) {
+                    return self.repeat_key(*kc);
                 }
-                return Ok(());
             }
         }
         Ok(())
     }
 
-    pub fn change_layer(&mut self, layer_name: String) {
+    /// Live reload the configuration once no keys are pressed anymore.
+    pub fn request_live_reload(&mut self) {
+        self.live_reload_requested = true;
+        self.wake();
+    }
+
+    /// Like `request_live_reload`, returning a receiver for the result of the reload. The caller
+    /// must not wait on it while holding the lock on kanata, since the processing loop needs it
+    /// to do the reload.
+    pub fn request_live_reload_result(&mut self) -> Receiver<Result<(), String>> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.reload_result_txs.push(tx);
+        self.request_live_reload();
+        rx
+    }
+
+    /// Switch to the configuration given to `--cfg` with the name `name` once no keys are pressed
+    /// anymore, keeping the current configuration if it fails to load. Returns a receiver for the
+    /// result like `request_live_reload_result`.
+    pub fn request_switch_config(&mut self, name: &str) -> Result<Receiver<Result<(), String>>> {
+        self.next_cfg_path = Some(self.cfg_path_by_name(name)?);
+        Ok(self.request_live_reload_result())
+    }
+
+    fn cfg_path_by_name(&self, name: &str) -> Result<PathBuf> {
+        self.cfg_paths
+            .iter()
+            .find(|path| cfg_name(path) == name)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown configuration {name}"))
+    }
+
+    pub fn change_layer(&mut self, layer_name: String) -> Result<()> {
         for (i, l) in self.layer_info.iter().enumerate() {
             if l.name == layer_name {
                 self.layout.set_default_layer(i);
-                return;
+                self.wake();
+                return Ok(());
+            }
+        }
+        bail!("unknown layer {layer_name}")
+    }
+
+    /// Name of the currently active layer.
+    pub fn current_layer_name(&self) -> String {
+        self.layer_info[self.layout.current_layer()].name.clone()
+    }
+
+    /// Names of all layers in the order they are defined in the configuration.
+    pub fn layer_names(&self) -> Vec<String> {
+        self.layer_info.iter().map(|l| l.name.clone()).collect()
+    }
+
+    /// Act on the fake key defined in `deffakekeys` with the given name.
+    pub fn handle_fake_key_action(&mut self, name: &str, action: FakeKeyAction) -> Result<()> {
+        let (x, y) = *self
+            .fake_keys
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown fake key {name}"))?;
+        log::debug!("fake key from client {action:?} {x:?},{y:?}");
+        match action {
+            FakeKeyAction::Press => self.layout.event(Event::Press(x, y)),
+            FakeKeyAction::Release => self.layout.event(Event::Release(x, y)),
+            FakeKeyAction::Tap => {
+                self.layout.event(Event::Press(x, y));
+                self.layout.event(Event::Release(x, y));
             }
         }
+        self.wake();
+        Ok(())
+    }
+
+    fn wake(&self) {
+        // The processing loop holds on to the receiver for as long as the instance lives.
+        let _ = self.wake_tx.try_send(());
     }
 
     fn check_handle_layer_change(&mut self, tx: &Option<Sender<ServerMessage>>) {
@@ -574,10 +1015,27 @@ impl Kanata {
         log::info!("Entered layer:\n\n{}", self.layer_info[layer].cfg_text);
     }
 
-    pub fn start_notification_loop(
-        rx: Receiver<ServerMessage>,
-        clients: Arc<Mutex<HashMap<String, TcpStream>>>,
-    ) {
+    /// Start a thread that requests a live reload whenever the configuration file or a file that it
+    /// includes changes.
+    pub fn start_file_watcher(kanata: Arc<Mutex<Self>>) {
+        info!("watching the configuration files for changes");
+        std::thread::spawn(move || {
+            let mut watcher = FileWatcher::default();
+            loop {
+                let files = kanata.lock().cfg_files.clone();
+                let changed = watcher.changed(&files);
+                if !changed.is_empty() {
+                    for path in changed {
+                        info!("{} changed", path.to_string_lossy());
+                    }
+                    kanata.lock().request_live_reload();
+                }
+                std::thread::sleep(FILE_WATCH_INTERVAL);
+            }
+        });
+    }
+
+    pub fn start_notification_loop(rx: Receiver<ServerMessage>, clients: Connections) {
         info!("listening for event notifications to relay to connected clients");
         std::thread::spawn(move || {
             loop {
@@ -589,10 +1047,7 @@ impl Kanata {
                         let notification = match event.as_bytes() {
                             Ok(serialized_notification) => serialized_notification,
                             Err(error) => {
-                                log::warn!(
-                                    "failed to serialize layer change notification: {}",
-                                    error
-                                );
+                                log::warn!("failed to serialize notification: {}", error);
                                 return;
                             }
                         };
@@ -600,9 +1055,12 @@ impl Kanata {
                         let mut clients = clients.lock();
                         let mut stale_clients = vec![];
                         for (id, client) in &mut *clients {
-                            match client.write(&notification) {
+                            if !client.wants(&event) {
+                                continue;
+                            }
+                            match client.writer.lock().write_all(&notification) {
                                 Ok(_) => {
-                                    log::debug!("layer change notification sent");
+                                    log::debug!("notification sent");
                                 }
                                 Err(_) => {
                                     // the client is no longer connected, let's remove them
@@ -641,54 +1099,78 @@ impl Kanata {
                 std::thread::sleep(time::Duration::from_millis(1));
             }
 
-            info!("Starting kanata proper");
-            let err = loop {
-                if kanata.lock().can_block() {
-                    log::trace!("blocking on channel");
-                    match rx.recv() {
-                        Ok(kev) => {
-                            let mut k = kanata.lock();
-                            k.last_tick = time::Instant::now()
-                                .checked_sub(time::Duration::from_millis(1))
-                                .unwrap();
-                            if let Err(e) = k.handle_key_event(&kev) {
-                                break e;
-                            }
-                            if let Err(e) = k.handle_time_ticks(&tx) {
-                                break e;
-                            }
+            Self::processing_loop(kanata, rx, tx)
+        });
+    }
+
+    /// Handle the key events from `rx` and advance time until `rx` is disconnected. Panics if
+    /// processing fails.
+    fn processing_loop(
+        kanata: Arc<Mutex<Self>>,
+        rx: Receiver<KeyEvent>,
+        tx: Option<Sender<ServerMessage>>,
+    ) {
+        info!("Starting kanata proper");
+        let wake_rx = kanata.lock().wake_rx.clone();
+        let err = loop {
+            if kanata.lock().can_block() {
+                log::trace!("blocking on channel");
+                let kev = crossbeam_channel::select! {
+                    recv(rx) -> kev => kev,
+                    recv(wake_rx) -> _ => {
+                        let mut k = kanata.lock();
+                        k.last_tick = time::Instant::now()
+                            .checked_sub(time::Duration::from_millis(1))
+                            .unwrap();
+                        if let Err(e) = k.handle_time_ticks(&tx) {
+                            break e;
                         }
-                        Err(_) => {
-                            log::error!("channel disconnected");
-                            return;
+                        continue;
+                    }
+                };
+                match kev {
+                    Ok(kev) => {
+                        let mut k = kanata.lock();
+                        k.last_tick = time::Instant::now()
+                            .checked_sub(time::Duration::from_millis(1))
+                            .unwrap();
+                        if let Err(e) = k.handle_key_event(&kev) {
+                            break e;
+                        }
+                        if let Err(e) = k.handle_time_ticks(&tx) {
+                            break e;
                         }
                     }
-                } else {
-                    let mut k = kanata.lock();
-                    match rx.try_recv() {
-                        Ok(kev) => {
-                            if let Err(e) = k.handle_key_event(&kev) {
-                                break e;
-                            }
-                            if let Err(e) = k.handle_time_ticks(&tx) {
-                                break e;
-                            }
+                    Err(_) => {
+                        log::error!("channel disconnected");
+                        return;
+                    }
+                }
+            } else {
+                let mut k = kanata.lock();
+                match rx.try_recv() {
+                    Ok(kev) => {
+                        if let Err(e) = k.handle_key_event(&kev) {
+                            break e;
                         }
-                        Err(TryRecvError::Empty) => {
-                            if let Err(e) = k.handle_time_ticks(&tx) {
-                                break e;
-                            }
-                            std::thread::sleep(time::Duration::from_millis(1));
+                        if let Err(e) = k.handle_time_ticks(&tx) {
+                            break e;
                         }
-                        Err(TryRecvError::Disconnected) => {
-                            log::error!("channel disconnected");
-                            return;
+                    }
+                    Err(TryRecvError::Empty) => {
+                        if let Err(e) = k.handle_time_ticks(&tx) {
+                            break e;
                         }
+                        std::thread::sleep(time::Duration::from_millis(1));
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        log::error!("channel disconnected");
+                        return;
                     }
                 }
-            };
-            panic!("processing loop encountered error {:?}", err)
-        });
+            }
+        };
+        panic!("processing loop encountered error {:?}", err)
     }
 
     pub fn can_block(&self) -> bool {
@@ -699,6 +1181,114 @@ impl Kanata {
             && self.layout.active_sequences.is_empty()
             && self.scroll_state.is_none()
             && self.hscroll_state.is_none()
+            && self.chord_state.is_none()
+            && self.caps_word_state.is_none()
+    }
+}
+
+/// How often the configuration files are checked for changes.
+const FILE_WATCH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Tracks the modification times of files to tell which ones changed.
+#[derive(Default)]
+struct FileWatcher {
+    /// The last seen modification time of each file, or `None` if it could not be read.
+    mtimes: HashMap<PathBuf, Option<time::SystemTime>>,
+}
+
+impl FileWatcher {
+    /// Returns the `paths` that changed since the last call. Paths that were not passed in the
+    /// last call are only recorded.
+    fn changed(&mut self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        let mut mtimes = HashMap::default();
+        for path in paths {
+            let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            if matches!(self.mtimes.get(path), Some(prev) if *prev != mtime) {
+                changed.push(path.clone());
+            }
+            mtimes.insert(path.clone(), mtime);
+        }
+        self.mtimes = mtimes;
+        changed
+    }
+}
+
+/// Validate what is only checked when kanata starts or reloads, i.e. the `defcfg` items and the
+/// `switch-config` targets given the configurations in `cfg_paths`, so that `--check` reports the
+/// same errors.
+pub fn check_cfg(cfg: &cfg::Cfg, cfg_paths: &[PathBuf]) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    parse_win_altgr_behaviour(cfg)?;
+    parse_sequence_timeout(cfg)?;
+    check_switch_configs(cfg, cfg_paths)
+}
+
+/// Check that every `switch-config` action switches to a configuration given to `--cfg`.
+fn check_switch_configs(cfg: &cfg::Cfg, cfg_paths: &[PathBuf]) -> Result<()> {
+    for name in &cfg.switch_configs {
+        if !cfg_paths.iter().any(|path| &cfg_name(path) == name) {
+            bail!("switch-config uses {name}, which is not the name of a configuration given to --cfg");
+        }
+    }
+    Ok(())
+}
+
+fn parse_sequence_timeout(cfg: &cfg::Cfg) -> Result<u16> {
+    Ok(cfg
+        .items
+        .get("sequence-timeout")
+        .map(|s| str::parse::<u16>(s))
+        .transpose()
+        .map_err(|e| anyhow!("{SEQUENCE_TIMEOUT_ERR}: {e:?}"))?
+        .map(|i| match i {
+            0 => Err(anyhow!("{SEQUENCE_TIMEOUT_ERR}")),
+            _ => Ok(i),
+        })
+        .transpose()?
+        .unwrap_or(SEQUENCE_TIMEOUT_DEFAULT))
+}
+
+/// Name of the configuration at `path` for `switch-config`: its file name without the extension.
+pub fn cfg_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the configuration text of each layer by name.
+fn layer_texts(layer_info: &[LayerInfo]) -> BTreeMap<String, String> {
+    layer_info
+        .iter()
+        .map(|l| (l.name.clone(), l.cfg_text.clone()))
+        .collect()
+}
+
+/// Returns which of the definitions of `kind` were added, removed or changed between the `old` and
+/// `new` configuration, which map their names to their configuration text.
+fn reload_changes(
+    kind: &str,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut changes = vec![];
+    for (name, text) in new {
+        match old.get(name) {
+            None => changes.push(format!("{kind} {name} added")),
+            Some(old_text) if old_text != text => changes.push(format!("{kind} {name} changed")),
+            Some(_) => {}
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        changes.push(format!("{kind} {name} removed"));
+    }
+    changes
+}
+
+fn log_reload_changes(kind: &str, old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) {
+    for change in reload_changes(kind, old, new) {
+        log::info!("{change}");
     }
 }
 
@@ -709,9 +1299,9 @@ fn set_altgr_behaviour(_cfg: &cfg::Cfg) -> Result<()> {
 }
 
 #[cfg(feature = "cmd")]
-fn run_cmd(cmd_and_args: &'static [String]) -> std::thread::JoinHandle<()> {
+fn run_cmd(cmd_and_args: Vec<String>) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
-        let mut args = cmd_and_args.iter().cloned();
+        let mut args = cmd_and_args.into_iter();
         let mut cmd = std::process::Command::new(
             args.next()
                 .expect("parsing should have forbidden empty cmd"),
@@ -742,7 +1332,7 @@ fn run_cmd(cmd_and_args: &'static [String]) -> std::thread::JoinHandle<()> {
 }
 
 #[cfg(feature = "cmd")]
-fn run_multi_cmd(cmds: Vec<&'static [String]>) {
+fn run_multi_cmd(cmds: Vec<Vec<String>>) {
     std::thread::spawn(move || {
         for cmd in cmds {
             if let Err(e) = run_cmd(cmd).join() {
@@ -753,7 +1343,7 @@ fn run_multi_cmd(cmds: Vec<&'static [String]>) {
 }
 
 #[cfg(not(feature = "cmd"))]
-fn run_multi_cmd(_cmds: Vec<&'static [String]>) {}
+fn run_multi_cmd(_cmds: Vec<Vec<String>>) {}
 
 /// Checks if kanata should exit based on the fixed key combination of:
 /// Lctl+Spc+Esc
@@ -786,3 +1376,451 @@ fn check_for_exit(event: &KeyEvent) {
         }
     }
 }
+
+#[test]
+fn processing_loop_sends_to_output() {
+    let cfg_path = PathBuf::from("./cfg_samples/minimal.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let output = SimulatedOutput::new();
+    let kanata = Kanata::new_with_output(cfg_path, cfg, Box::new(output.clone())).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    // Skip the initial period of start_processing_loop where only releases are handled.
+    let handle =
+        std::thread::spawn(move || Kanata::processing_loop(Arc::new(Mutex::new(kanata)), rx, None));
+
+    // Wait for the output of each tap since the tap-hold on caps would resolve to hold if j was
+    // pressed before the tap is processed.
+    let wait_for_events = |count| {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        let mut events = vec![];
+        while events.len() < count && time::Instant::now() < deadline {
+            events.extend(output.take_events());
+            std::thread::sleep(time::Duration::from_millis(1));
+        }
+        events
+    };
+    for code in [OsCode::KEY_CAPSLOCK, OsCode::KEY_J] {
+        for value in [KeyValue::Press, KeyValue::Release] {
+            tx.send(KeyEvent::new(code, value)).unwrap();
+        }
+        assert_eq!(
+            wait_for_events(2),
+            vec![
+                OutputEvent::Key(code, KeyValue::Press),
+                OutputEvent::Key(code, KeyValue::Release),
+            ]
+        );
+    }
+
+    // The loop ends once the channel is disconnected.
+    drop(tx);
+    handle.join().unwrap();
+}
+
+#[test]
+fn device_events_use_device_layers() {
+    let cfg_path = PathBuf::from("./test_cfgs/devices.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let output = SimulatedOutput::new();
+    let mut kanata = Kanata::new_with_output(cfg_path, cfg, Box::new(output.clone())).unwrap();
+    let mut tap = |device| {
+        for value in [KeyValue::Press, KeyValue::Release] {
+            let event = KeyEvent {
+                code: OsCode::KEY_A,
+                value,
+                device,
+            };
+            kanata.handle_key_event(&event).unwrap();
+            kanata.tick_ms(1, &None).unwrap();
+        }
+        output.take_events()[0]
+    };
+    let press = |code| OutputEvent::Key(code, KeyValue::Press);
+    assert_eq!(tap(None), press(OsCode::KEY_A));
+    assert_eq!(tap(Some(0)), press(OsCode::KEY_X));
+    assert_eq!(tap(Some(1)), press(OsCode::KEY_Y));
+    // A device index that is no longer part of the configuration falls back to the top level.
+    assert_eq!(tap(Some(2)), press(OsCode::KEY_A));
+}
+
+#[test]
+fn ticks_send_notifications() {
+    let cfg_path = PathBuf::from("./test_cfgs/notifications.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let tx = Some(tx);
+    let tap = |kanata: &mut Kanata, code, ms| {
+        for value in [KeyValue::Press, KeyValue::Release] {
+            kanata
+                .handle_key_event(&KeyEvent::new(code, value))
+                .unwrap();
+            kanata.tick_ms(ms, &tx).unwrap();
+        }
+    };
+    let key_output = |key: &str, pressed| ServerMessage::KeyOutput {
+        key: key.into(),
+        pressed,
+    };
+
+    tap(&mut kanata, OsCode::KEY_A, 1);
+    tap(&mut kanata, OsCode::KEY_B, 1);
+    tap(&mut kanata, OsCode::KEY_D, 5);
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            ServerMessage::SequenceEnter {},
+            // Keys typed in sequence mode are not pressed on the OS output but are released.
+            key_output("KEY_B", false),
+            ServerMessage::SequenceComplete {},
+            key_output("KEY_D", false),
+            key_output("KEY_X", true),
+            key_output("KEY_X", false),
+        ]
+    );
+
+    tap(&mut kanata, OsCode::KEY_A, 150);
+    tap(&mut kanata, OsCode::KEY_C, 100);
+    kanata.do_live_reload().unwrap();
+    kanata.tick_ms(1, &tx).unwrap();
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            ServerMessage::SequenceEnter {},
+            ServerMessage::SequenceExit {},
+            key_output("KEY_LEFTSHIFT", true),
+            ServerMessage::OneShot { active: true },
+            ServerMessage::OneShot { active: false },
+            key_output("KEY_LEFTSHIFT", false),
+            ServerMessage::ReloadSuccess {},
+        ]
+    );
+
+    tap(&mut kanata, OsCode::KEY_E, 100);
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            ServerMessage::CapsWord { active: true },
+            ServerMessage::CapsWord { active: false },
+        ]
+    );
+
+    for value in [KeyValue::Press, KeyValue::Repeat, KeyValue::Release] {
+        kanata
+            .handle_key_event(&KeyEvent::new(OsCode::KEY_D, value))
+            .unwrap();
+        kanata.tick_ms(1, &tx).unwrap();
+    }
+    tap(&mut kanata, OsCode::KEY_G, 1);
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            key_output("KEY_D", true),
+            ServerMessage::KeyRepeat {
+                key: "KEY_D".into()
+            },
+            key_output("KEY_D", false),
+            ServerMessage::UnicodeOutput { text: "é".into() },
+        ]
+    );
+
+    // The repeated key is the last key pressed by any instance, so only check that it is
+    // released, pressed and released again.
+    tap(&mut kanata, OsCode::KEY_F, 1);
+    let repeated = rx.try_iter().collect::<Vec<_>>();
+    assert!(
+        matches!(
+            &repeated[..],
+            [
+                ServerMessage::KeyOutput { pressed: false, .. },
+                ServerMessage::KeyOutput { pressed: true, .. },
+                ServerMessage::KeyOutput { pressed: false, .. },
+            ]
+        ),
+        "{repeated:?}"
+    );
+}
+
+#[test]
+fn reload_keeps_layer_and_releases_keys() {
+    let cfg_path = PathBuf::from("./test_cfgs/reload_before.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let output = SimulatedOutput::new();
+    let mut kanata = Kanata::new_with_output(cfg_path, cfg, Box::new(output.clone())).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let tx = Some(tx);
+    for value in [KeyValue::Press, KeyValue::Release] {
+        kanata
+            .handle_key_event(&KeyEvent::new(OsCode::KEY_A, value))
+            .unwrap();
+        kanata.tick_ms(1, &tx).unwrap();
+    }
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Press))
+        .unwrap();
+    kanata.tick_ms(1, &tx).unwrap();
+    assert_eq!(kanata.current_layer_name(), "nav");
+    assert_eq!(
+        output.take_events(),
+        vec![OutputEvent::Key(OsCode::KEY_B, KeyValue::Press)]
+    );
+    rx.try_iter().for_each(drop);
+
+    kanata.cfg_path = PathBuf::from("./test_cfgs/reload_after.kbd");
+    kanata.do_live_reload().unwrap();
+    kanata.tick_ms(1, &tx).unwrap();
+    assert_eq!(kanata.current_layer_name(), "nav");
+    assert_eq!(kanata.layout.default_layer, 2);
+    assert_eq!(
+        output.take_events(),
+        vec![OutputEvent::Key(OsCode::KEY_B, KeyValue::Release)]
+    );
+    // The layer did not change, so there is no layer change notification.
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            ServerMessage::KeyOutput {
+                key: "KEY_B".into(),
+                pressed: false
+            },
+            ServerMessage::ReloadSuccess {},
+        ]
+    );
+}
+
+#[test]
+fn reload_frees_the_previous_configuration() {
+    let cfg_path = PathBuf::from("./test_cfgs/reload_before.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    let allocations = kanata.layout.allocations();
+    kanata.cfg_path = PathBuf::from("./test_cfgs/missing.kbd");
+    assert!(kanata.do_live_reload().is_err());
+    assert_eq!(allocations.strong_count(), 2);
+    kanata.cfg_path = PathBuf::from("./test_cfgs/reload_after.kbd");
+    kanata.do_live_reload().unwrap();
+    assert_eq!(allocations.strong_count(), 0);
+}
+
+#[test]
+fn reload_twice_after_removing_the_active_layer() {
+    let cfg_path = PathBuf::from("./test_cfgs/reload_before.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let tx = Some(tx);
+    for value in [KeyValue::Press, KeyValue::Release] {
+        kanata
+            .handle_key_event(&KeyEvent::new(OsCode::KEY_A, value))
+            .unwrap();
+        kanata.tick_ms(1, &tx).unwrap();
+    }
+    assert_eq!(kanata.current_layer_name(), "nav");
+    rx.try_iter().for_each(drop);
+
+    // The active layer is gone, and reloading again before the next tick must not look it up.
+    kanata.cfg_path = PathBuf::from("./test_cfgs/reload_removed_layer.kbd");
+    kanata.do_live_reload().unwrap();
+    kanata.do_live_reload().unwrap();
+    kanata.tick_ms(1, &tx).unwrap();
+    assert_eq!(kanata.current_layer_name(), "base");
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            ServerMessage::LayerChange { new: "base".into() },
+            ServerMessage::ReloadSuccess {},
+            ServerMessage::ReloadSuccess {},
+        ]
+    );
+}
+
+#[test]
+fn reload_changes_by_name() {
+    let texts = |defs: &[(&str, &str)]| {
+        defs.iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        reload_changes(
+            "layer",
+            &texts(&[("base", "(deflayer base a)"), ("nav", "(deflayer nav a)")]),
+            &texts(&[("added", "(deflayer added a)"), ("nav", "(deflayer nav x)")]),
+        ),
+        vec![
+            "layer added added",
+            "layer nav changed",
+            "layer base removed"
+        ]
+    );
+}
+
+#[test]
+fn file_watcher_reports_changed_files() {
+    let path = std::env::temp_dir().join(format!("kanata-watch-{}.kbd", std::process::id()));
+    std::fs::write(&path, "(defcfg)").unwrap();
+    let set_mtime = |secs| {
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(secs))
+            .unwrap()
+    };
+    set_mtime(1000);
+    let paths = vec![path.clone()];
+    let mut watcher = FileWatcher::default();
+    assert!(watcher.changed(&paths).is_empty());
+    assert!(watcher.changed(&paths).is_empty());
+    set_mtime(2000);
+    assert_eq!(watcher.changed(&paths), paths);
+    assert!(watcher.changed(&paths).is_empty());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(watcher.changed(&paths), paths);
+}
+
+#[test]
+fn check_cfg_validates_defcfg_items_and_switch_configs() {
+    let path = std::env::temp_dir().join(format!("kanata-check-{}.kbd", std::process::id()));
+    let cfg_paths = [path.clone()];
+    let check = |defcfg: &str| {
+        std::fs::write(&path, format!("{defcfg} (defsrc a) (deflayer base b)")).unwrap();
+        check_cfg(&cfg::Cfg::new_from_file(&path).unwrap(), &cfg_paths)
+    };
+    assert!(check("(defcfg sequence-timeout 100)").is_ok());
+    assert!(check("(defcfg sequence-timeout 0)").is_err());
+    assert!(check("(defcfg sequence-timeout soon)").is_err());
+    std::fs::remove_file(&path).unwrap();
+
+    // switch_coding switches to switch_gaming.
+    let cfg = cfg::Cfg::new_from_file(Path::new("./test_cfgs/switch_coding.kbd")).unwrap();
+    let check = |cfg_paths: &[&str]| {
+        let cfg_paths = cfg_paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        check_cfg(&cfg, &cfg_paths)
+    };
+    let err = check(&["./test_cfgs/switch_coding.kbd"]).unwrap_err();
+    assert!(err.to_string().contains("switch_gaming"), "{err}");
+    assert!(check(&[
+        "./test_cfgs/switch_coding.kbd",
+        "./test_cfgs/switch_gaming.kbd"
+    ])
+    .is_ok());
+}
+
+#[test]
+fn requested_reload_waits_for_released_keys() {
+    let cfg_path = PathBuf::from("./test_cfgs/reload_before.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    assert_eq!(
+        kanata.cfg_files,
+        vec![PathBuf::from("./test_cfgs/reload_before.kbd")]
+    );
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let tx = Some(tx);
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Press))
+        .unwrap();
+    kanata.tick_ms(1, &tx).unwrap();
+    kanata.cfg_path = PathBuf::from("./test_cfgs/reload_after.kbd");
+    kanata.request_live_reload();
+    kanata.tick_ms(5, &tx).unwrap();
+    assert_eq!(kanata.layer_names(), vec!["base", "nav"]);
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Release))
+        .unwrap();
+    kanata.tick_ms(2, &tx).unwrap();
+    assert_eq!(kanata.layer_names(), vec!["added", "base", "nav"]);
+    assert!(rx.try_iter().any(|m| m == ServerMessage::ReloadSuccess {}));
+}
+
+#[test]
+fn reload_result_is_sent_after_the_reload() {
+    let cfg_path = PathBuf::from("./test_cfgs/reload_before.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Press))
+        .unwrap();
+    kanata.tick_ms(1, &None).unwrap();
+    let result = kanata.request_live_reload_result();
+    kanata.tick_ms(5, &None).unwrap();
+    assert_eq!(result.try_recv(), Err(TryRecvError::Empty));
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Release))
+        .unwrap();
+    kanata.tick_ms(2, &None).unwrap();
+    assert_eq!(result.try_recv(), Ok(Ok(())));
+
+    kanata.cfg_path = PathBuf::from("./test_cfgs/missing.kbd");
+    let result = kanata.request_live_reload_result();
+    kanata.tick_ms(1, &None).unwrap();
+    assert!(matches!(result.try_recv(), Ok(Err(_))));
+}
+
+#[test]
+fn switch_config_by_name() {
+    let cfg_paths = vec![
+        PathBuf::from("./test_cfgs/switch_coding.kbd"),
+        PathBuf::from("./test_cfgs/switch_gaming.kbd"),
+    ];
+    let cfg = cfg::Cfg::new_from_file(&cfg_paths[0]).unwrap();
+    let output = SimulatedOutput::new();
+    let kanata =
+        Kanata::new_with_output(cfg_paths[0].clone(), cfg, Box::new(output.clone())).unwrap();
+    let mut kanata = Kanata {
+        cfg_paths: cfg_paths.clone(),
+        ..kanata
+    };
+    let tap = |kanata: &mut Kanata, code| {
+        for value in [KeyValue::Press, KeyValue::Release] {
+            kanata
+                .handle_key_event(&KeyEvent::new(code, value))
+                .unwrap();
+            kanata.tick_ms(1, &None).unwrap();
+        }
+    };
+
+    tap(&mut kanata, OsCode::KEY_A);
+    tap(&mut kanata, OsCode::KEY_B);
+    assert_eq!(kanata.cfg_path, cfg_paths[1]);
+    assert_eq!(kanata.layer_names(), vec!["gaming"]);
+    assert_eq!(
+        output.take_events(),
+        vec![
+            OutputEvent::Key(OsCode::KEY_Y, KeyValue::Press),
+            OutputEvent::Key(OsCode::KEY_Y, KeyValue::Release),
+        ]
+    );
+
+    assert!(kanata.request_switch_config("unknown").is_err());
+    assert_eq!(kanata.cfg_path, cfg_paths[1]);
+    // Configurations that switch to a configuration not given to --cfg are refused.
+    kanata
+        .cfg_paths
+        .push(PathBuf::from("./test_cfgs/switch_unknown.kbd"));
+    let result = kanata.request_switch_config("switch_unknown").unwrap();
+    kanata.tick_ms(1, &None).unwrap();
+    let err = result.try_recv().unwrap().unwrap_err();
+    assert!(err.contains("switch_missing"), "{err}");
+    assert_eq!(kanata.cfg_path, cfg_paths[1]);
+    // The switch waits for held keys to be released.
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Press))
+        .unwrap();
+    let result = kanata.request_switch_config("switch_coding").unwrap();
+    kanata.tick_ms(1, &None).unwrap();
+    assert_eq!(kanata.cfg_path, cfg_paths[1]);
+    kanata
+        .handle_key_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Release))
+        .unwrap();
+    kanata.tick_ms(2, &None).unwrap();
+    assert_eq!(result.try_recv(), Ok(Ok(())));
+    assert_eq!(kanata.cfg_path, cfg_paths[0]);
+    assert_eq!(kanata.layer_names(), vec!["coding"]);
+}
@@ -0,0 +1,405 @@
+//! Runs a configuration against a scripted sequence of input events without touching any OS
+//! devices, which is useful for checking what a configuration does.
+
+use std::path::Path;
+
+use super::*;
+
+/// An item of a simulation script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimEvent {
+    Key(OsCode, KeyValue),
+    /// Let the given number of milliseconds pass.
+    Wait(u32),
+}
+
+/// Parse a simulation script.
+///
+/// Items are separated by whitespace and `;;` starts a comment that lasts until the end of the
+/// line. Each item is one of:
+///
+/// - `d:<key>` or `press:<key>`
+/// - `u:<key>` or `release:<key>`
+/// - `r:<key>` or `repeat:<key>`
+/// - `t:<ms>` or `wait:<ms>`
+pub fn parse_sim_script(script: &str) -> Result<Vec<SimEvent>> {
+    let mut events = vec![];
+    for line in script.lines() {
+        let line = match line.find(";;") {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        for item in line.split_whitespace() {
+            let (kind, val) = item.split_once(':').ok_or_else(|| {
+                anyhow!("invalid simulation item {item}: expected <kind>:<value>")
+            })?;
+            let key = || {
+                str_to_oscode(val)
+                    .ok_or_else(|| anyhow!("invalid simulation item {item}: unknown key {val}"))
+            };
+            events.push(match kind {
+                "d" | "press" => SimEvent::Key(key()?, KeyValue::Press),
+                "u" | "release" => SimEvent::Key(key()?, KeyValue::Release),
+                "r" | "repeat" => SimEvent::Key(key()?, KeyValue::Repeat),
+                "t" | "wait" => SimEvent::Wait(val.parse().map_err(|_| {
+                    anyhow!("invalid simulation item {item}: expected a number of milliseconds")
+                })?),
+                _ => bail!("invalid simulation item {item}: unknown kind {kind}"),
+            });
+        }
+    }
+    Ok(events)
+}
+
+impl Kanata {
+    /// Run the simulation script at `script_path` against the configuration at `cfg_path` and
+    /// print each output event to stdout along with the simulated time it was sent at.
+    pub fn simulate(cfg_path: &Path, script_path: &Path) -> Result<()> {
+        let script = std::fs::read_to_string(script_path).map_err(|e| {
+            anyhow!(
+                "could not read simulation file {}: {e}",
+                script_path.to_string_lossy()
+            )
+        })?;
+        let events = parse_sim_script(&script)?;
+        let cfg = cfg::Cfg::new_from_file(cfg_path)?;
+        let output = SimulatedOutput::new();
+        let mut kanata = Self::new_with_output(cfg_path.into(), cfg, Box::new(output.clone()))?;
+        for (ms, event) in kanata.run_simulation(&events, &output)? {
+            println!("{ms}ms: {event}");
+        }
+        Ok(())
+    }
+
+    /// Feed `events` through the same processing as live input events and return what was sent
+    /// to `output`, which must be the output this instance writes to. Time only advances on
+    /// `SimEvent::Wait`, one millisecond at a time.
+    pub fn run_simulation(
+        &mut self,
+        events: &[SimEvent],
+        output: &SimulatedOutput,
+    ) -> Result<Vec<(u64, OutputEvent)>> {
+        let mut ms = 0;
+        let mut outputs = vec![];
+        for event in events {
+            match *event {
+                SimEvent::Key(code, value) => {
+                    if self.mapped_keys.contains(&code) {
+                        self.handle_key_event(&KeyEvent::new(code, value))?;
+                    } else {
+                        self.kbd_out.write_key(code, value)?;
+                    }
+                }
+                SimEvent::Wait(wait) => {
+                    for _ in 0..wait {
+                        ms += 1;
+                        self.tick_ms(1, &None)?;
+                        outputs.extend(output.take_events().into_iter().map(|ev| (ms, ev)));
+                    }
+                }
+            }
+            outputs.extend(output.take_events().into_iter().map(|ev| (ms, ev)));
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+fn simulate_cfg(cfg_path: &str, script: &str) -> Vec<(u64, OutputEvent)> {
+    let cfg = cfg::Cfg::new_from_file(Path::new(cfg_path)).unwrap();
+    let output = SimulatedOutput::new();
+    let mut kanata =
+        Kanata::new_with_output(cfg_path.into(), cfg, Box::new(output.clone())).unwrap();
+    kanata
+        .run_simulation(&parse_sim_script(script).unwrap(), &output)
+        .unwrap()
+}
+
+#[test]
+fn parse_sim_script_items() {
+    let events = parse_sim_script(
+        "d:a t:10 ;; comment d:b
+        release:a r:lctl wait:5",
+    )
+    .unwrap();
+    assert_eq!(
+        events,
+        vec![
+            SimEvent::Key(OsCode::KEY_A, KeyValue::Press),
+            SimEvent::Wait(10),
+            SimEvent::Key(OsCode::KEY_A, KeyValue::Release),
+            SimEvent::Key(OsCode::KEY_LEFTCTRL, KeyValue::Repeat),
+            SimEvent::Wait(5),
+        ]
+    );
+    assert!(parse_sim_script("d:notakey").is_err());
+    assert!(parse_sim_script("x:a").is_err());
+    assert!(parse_sim_script("t:abc").is_err());
+    assert!(parse_sim_script("a").is_err());
+}
+
+#[test]
+fn simulate_tap_hold() {
+    use OsCode::*;
+    let outputs = simulate_cfg(
+        "./cfg_samples/minimal.kbd",
+        "d:caps t:50 u:caps t:300 d:caps t:300 u:caps t:10 d:z u:z",
+    );
+    let events: Vec<_> = outputs.iter().map(|(_, ev)| *ev).collect();
+    assert_eq!(
+        events,
+        vec![
+            OutputEvent::Key(KEY_CAPSLOCK, KeyValue::Press),
+            OutputEvent::Key(KEY_CAPSLOCK, KeyValue::Release),
+            OutputEvent::Key(KEY_LEFTCTRL, KeyValue::Press),
+            OutputEvent::Key(KEY_LEFTCTRL, KeyValue::Release),
+            OutputEvent::Key(KEY_Z, KeyValue::Press),
+            OutputEvent::Key(KEY_Z, KeyValue::Release),
+        ]
+    );
+    // The hold action activates once the hold timeout expires.
+    assert!(matches!(outputs[2], (ms, _) if (550..=610).contains(&ms)));
+}
+
+#[test]
+fn simulate_chords() {
+    use OsCode::*;
+    let outputs = simulate_cfg("./test_cfgs/chords.kbd", "d:j t:10 d:k t:100 u:j u:k t:10");
+    let events: Vec<_> = outputs.iter().map(|(_, ev)| *ev).collect();
+    assert_eq!(
+        events,
+        vec![
+            OutputEvent::Key(KEY_ESC, KeyValue::Press),
+            OutputEvent::Key(KEY_ESC, KeyValue::Release),
+        ]
+    );
+
+    // j+k+l is a larger chord than j+k, which is defined first.
+    let outputs = simulate_cfg(
+        "./test_cfgs/chords.kbd",
+        "d:j t:5 d:k t:5 d:l t:100 u:j u:k u:l t:10",
+    );
+    let events: Vec<_> = outputs.iter().map(|(_, ev)| *ev).collect();
+    assert_eq!(
+        events,
+        vec![
+            OutputEvent::Key(KEY_H, KeyValue::Press),
+            OutputEvent::Key(KEY_H, KeyValue::Release),
+            OutputEvent::Key(KEY_I, KeyValue::Press),
+            OutputEvent::Key(KEY_I, KeyValue::Release),
+        ]
+    );
+}
+
+#[test]
+fn simulate_many_layers() {
+    use OsCode::*;
+    let mut cfg = String::from("(defcfg) (defsrc a b c) (deflayer base (layer-switch l39) b c)");
+    for i in 1..39 {
+        cfg.push_str(&format!(" (deflayer l{i} _ _ _)"));
+    }
+    cfg.push_str(" (deflayer l39 x y _)");
+    let path = std::env::temp_dir().join(format!("kanata-layers-{}.kbd", std::process::id()));
+    std::fs::write(&path, cfg).unwrap();
+    let outputs = simulate_cfg(
+        path.to_str().unwrap(),
+        "d:a t:10 u:a t:10 d:b t:10 u:b t:10 d:c t:10 u:c t:10",
+    );
+    std::fs::remove_file(&path).unwrap();
+    let events: Vec<_> = outputs.iter().map(|(_, ev)| *ev).collect();
+    assert_eq!(
+        events,
+        vec![
+            OutputEvent::Key(KEY_Y, KeyValue::Press),
+            OutputEvent::Key(KEY_Y, KeyValue::Release),
+            // Transparent keys on the last layer fall back to defsrc.
+            OutputEvent::Key(KEY_C, KeyValue::Press),
+            OutputEvent::Key(KEY_C, KeyValue::Release),
+        ]
+    );
+}
+
+#[test]
+fn simulate_caps_word() {
+    use OsCode::*;
+    let tap = |k: OsCode| {
+        vec![
+            OutputEvent::Key(k, KeyValue::Press),
+            OutputEvent::Key(k, KeyValue::Release),
+        ]
+    };
+    let shifted = |k: OsCode| {
+        vec![
+            OutputEvent::Key(KEY_LEFTSHIFT, KeyValue::Press),
+            OutputEvent::Key(k, KeyValue::Press),
+            OutputEvent::Key(k, KeyValue::Release),
+            OutputEvent::Key(KEY_LEFTSHIFT, KeyValue::Release),
+        ]
+    };
+    let events = |script| -> Vec<_> {
+        simulate_cfg("./test_cfgs/caps_word.kbd", script)
+            .into_iter()
+            .map(|(_, ev)| ev)
+            .collect()
+    };
+
+    // Letters and - are shifted, digits are not, and space ends the word.
+    assert_eq!(
+        events(
+            "d:caps t:10 u:caps t:10 d:a t:10 u:a t:10 d:1 t:10 u:1 t:10 d:- t:10 u:- t:10
+             d:b t:10 u:b t:10 d:spc t:10 u:spc t:10 d:a t:10 u:a t:10"
+        ),
+        [
+            shifted(KEY_A),
+            tap(KEY_1),
+            shifted(KEY_MINUS),
+            shifted(KEY_B),
+            tap(KEY_SPACE),
+            tap(KEY_A),
+        ]
+        .concat()
+    );
+    // Caps-word ends after the timeout.
+    assert_eq!(
+        events("d:caps t:10 u:caps t:600 d:a t:10 u:a t:1100 d:b t:10 u:b t:10"),
+        [shifted(KEY_A), tap(KEY_B)].concat()
+    );
+    // Shift stays pressed while shifted keys are held, so that their autorepeat is shifted too.
+    // A key that is not shifted releases it first.
+    assert_eq!(
+        events("d:caps t:10 u:caps t:10 d:a t:10 r:a t:10 d:b t:10 u:a t:10 d:1 t:10 u:b u:1 t:10"),
+        [
+            OutputEvent::Key(KEY_LEFTSHIFT, KeyValue::Press),
+            OutputEvent::Key(KEY_A, KeyValue::Press),
+            OutputEvent::Key(KEY_A, KeyValue::Repeat),
+            OutputEvent::Key(KEY_B, KeyValue::Press),
+            OutputEvent::Key(KEY_A, KeyValue::Release),
+            OutputEvent::Key(KEY_LEFTSHIFT, KeyValue::Release),
+            OutputEvent::Key(KEY_1, KeyValue::Press),
+            OutputEvent::Key(KEY_B, KeyValue::Release),
+            OutputEvent::Key(KEY_1, KeyValue::Release),
+        ]
+    );
+    // Modifiers such as a bare ctrl end caps-word like any other key that is not listed.
+    assert_eq!(
+        events("d:caps t:10 u:caps t:10 d:lctl t:10 u:lctl t:10 d:a t:10 u:a t:10"),
+        [tap(KEY_LEFTCTRL), tap(KEY_A)].concat()
+    );
+}
+
+#[test]
+fn simulate_tap_hold_keys() {
+    use OsCode::*;
+    let events = |script| -> Vec<_> {
+        simulate_cfg("./test_cfgs/tap_hold_keys.kbd", script)
+            .into_iter()
+            .map(|(_, ev)| ev)
+            .collect()
+    };
+    let ev = |k, pressed| {
+        OutputEvent::Key(
+            k,
+            if pressed {
+                KeyValue::Press
+            } else {
+                KeyValue::Release
+            },
+        )
+    };
+
+    // A listed key resolves tap-hold-release-keys to tap right away.
+    assert_eq!(
+        events("d:a t:10 d:s t:10 u:s t:10 u:a t:10"),
+        vec![
+            ev(KEY_A, true),
+            ev(KEY_S, true),
+            ev(KEY_S, false),
+            ev(KEY_A, false)
+        ]
+    );
+    // Other keys resolve it to hold once they are pressed and released.
+    assert_eq!(
+        events("d:a t:10 d:spc t:10 u:spc t:10 u:a t:10"),
+        vec![
+            ev(KEY_LEFTMETA, true),
+            ev(KEY_SPACE, true),
+            ev(KEY_SPACE, false),
+            ev(KEY_LEFTMETA, false)
+        ]
+    );
+    // tap-hold-press-keys resolves to hold on a press of a listed key and to tap otherwise.
+    assert_eq!(
+        events("d:j t:10 d:s t:10 u:s t:10 u:j t:10"),
+        vec![
+            ev(KEY_RIGHTMETA, true),
+            ev(KEY_S, true),
+            ev(KEY_S, false),
+            ev(KEY_RIGHTMETA, false)
+        ]
+    );
+    assert_eq!(
+        events("d:j t:10 d:spc t:10 u:spc t:10 u:j t:10"),
+        vec![
+            ev(KEY_J, true),
+            ev(KEY_SPACE, true),
+            ev(KEY_SPACE, false),
+            ev(KEY_J, false)
+        ]
+    );
+}
+
+#[test]
+fn simulate_tap_hold_opposite_hand() {
+    use OsCode::*;
+    let events = |script| -> Vec<_> {
+        simulate_cfg("./test_cfgs/opposite_hand.kbd", script)
+            .into_iter()
+            .map(|(_, ev)| ev)
+            .collect()
+    };
+    let ev = |k, pressed| {
+        OutputEvent::Key(
+            k,
+            if pressed {
+                KeyValue::Press
+            } else {
+                KeyValue::Release
+            },
+        )
+    };
+
+    // A roll within the same hand taps.
+    assert_eq!(
+        events("d:a t:10 d:s t:10 u:a t:10 u:s t:10"),
+        vec![
+            ev(KEY_A, true),
+            ev(KEY_S, true),
+            ev(KEY_A, false),
+            ev(KEY_S, false)
+        ]
+    );
+    // A key of the other hand holds as soon as it is pressed.
+    assert_eq!(
+        events("d:a t:10 d:j t:10 u:a t:10 u:j t:10"),
+        vec![
+            ev(KEY_LEFTMETA, true),
+            ev(KEY_J, true),
+            ev(KEY_LEFTMETA, false),
+            ev(KEY_J, false)
+        ]
+    );
+    // A key of neither hand holds once it is pressed and released.
+    assert_eq!(
+        events("d:a t:10 d:spc t:10 u:spc t:10 u:a t:10"),
+        vec![
+            ev(KEY_LEFTMETA, true),
+            ev(KEY_SPACE, true),
+            ev(KEY_SPACE, false),
+            ev(KEY_LEFTMETA, false)
+        ]
+    );
+    assert_eq!(
+        events("d:a t:10 u:a t:10"),
+        vec![ev(KEY_A, true), ev(KEY_A, false)]
+    );
+}
@@ -43,7 +43,11 @@ impl Kanata {
                                 false => KeyValue::Press,
                                 true => KeyValue::Release,
                             };
-                            KeyEvent { code, value }
+                            KeyEvent {
+                                code,
+                                value,
+                                device: None,
+                            }
                         }
                         _ => {
                             intrcptn.send(dev, &strokes[i..i + 1]);
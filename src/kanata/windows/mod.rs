@@ -26,26 +26,28 @@ pub static ALTGR_BEHAVIOUR: Lazy<Mutex<AltGrBehaviour>> =
     Lazy::new(|| Mutex::new(AltGrBehaviour::DoNothing));
 
 pub fn set_win_altgr_behaviour(cfg: &cfg::Cfg) -> Result<()> {
-    *ALTGR_BEHAVIOUR.lock() = {
-        const CANCEL: &str = "cancel-lctl-press";
-        const ADD: &str = "add-lctl-release";
-        match cfg.items.get("windows-altgr") {
-            None => AltGrBehaviour::DoNothing,
-            Some(cfg_val) => match cfg_val.as_str() {
-                CANCEL => AltGrBehaviour::CancelLctlPress,
-                ADD => AltGrBehaviour::AddLctlRelease,
-                _ => bail!(
-                    "Invalid value for windows-altgr: {}. Valid values are {},{}",
-                    cfg_val,
-                    CANCEL,
-                    ADD
-                ),
-            },
-        }
-    };
+    *ALTGR_BEHAVIOUR.lock() = parse_win_altgr_behaviour(cfg)?;
     Ok(())
 }
 
+pub fn parse_win_altgr_behaviour(cfg: &cfg::Cfg) -> Result<AltGrBehaviour> {
+    const CANCEL: &str = "cancel-lctl-press";
+    const ADD: &str = "add-lctl-release";
+    Ok(match cfg.items.get("windows-altgr") {
+        None => AltGrBehaviour::DoNothing,
+        Some(cfg_val) => match cfg_val.as_str() {
+            CANCEL => AltGrBehaviour::CancelLctlPress,
+            ADD => AltGrBehaviour::AddLctlRelease,
+            _ => bail!(
+                "Invalid value for windows-altgr: {}. Valid values are {},{}",
+                cfg_val,
+                CANCEL,
+                ADD
+            ),
+        },
+    })
+}
+
 impl Kanata {
     pub fn check_release_non_physical_shift(&mut self) -> Result<()> {
         static PREV_STATES: Lazy<Mutex<Vec<State<&[&CustomAction]>>>> =
@@ -572,6 +572,7 @@ impl TryFrom<InputEvent> for KeyEvent {
             evdev::InputEventKind::Key(k) => Ok(Self {
                 code: OsCode::from_u16(k.0).ok_or(())?,
                 value: KeyValue::from(item.value()),
+                device: None,
             }),
             _ => Err(()),
         }
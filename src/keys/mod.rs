@@ -4,8 +4,6 @@ use kanata_keyberon::key_code::KeyCode;
 
 #[cfg(target_os = "linux")]
 mod linux;
-#[cfg(target_os = "linux")]
-pub use linux::*;
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -179,11 +177,11 @@ pub fn str_to_oscode(s: &str) -> Option<OsCode> {
         "mail" | "email" => OsCode::KEY_EMAIL,
 
         // NOTE: these are linux-only right now due to missing the mappings in windows.rs
-        #[cfg(tagret_os = "linux")]
+        #[cfg(target_os = "linux")]
         "plyr" | "player" => OsCode::KEY_PLAYER,
-        #[cfg(tagret_os = "linux")]
+        #[cfg(target_os = "linux")]
         "powr" | "power" => OsCode::KEY_POWER,
-        #[cfg(tagret_os = "linux")]
+        #[cfg(target_os = "linux")]
         "zzz" | "sleep" => OsCode::KEY_SLEEP,
 
         _ => return None,
@@ -746,6 +744,51 @@ pub enum OsCode {
     BTN_MAX = 744,
 }
 
+impl OsCode {
+    /// Whether this is one of the letter keys a to z.
+    pub fn is_letter(self) -> bool {
+        use OsCode::*;
+        matches!(
+            self,
+            KEY_A
+                | KEY_B
+                | KEY_C
+                | KEY_D
+                | KEY_E
+                | KEY_F
+                | KEY_G
+                | KEY_H
+                | KEY_I
+                | KEY_J
+                | KEY_K
+                | KEY_L
+                | KEY_M
+                | KEY_N
+                | KEY_O
+                | KEY_P
+                | KEY_Q
+                | KEY_R
+                | KEY_S
+                | KEY_T
+                | KEY_U
+                | KEY_V
+                | KEY_W
+                | KEY_X
+                | KEY_Y
+                | KEY_Z
+        )
+    }
+
+    /// Whether this is one of the number keys 0 to 9 above the letters.
+    pub fn is_digit(self) -> bool {
+        use OsCode::*;
+        matches!(
+            self,
+            KEY_0 | KEY_1 | KEY_2 | KEY_3 | KEY_4 | KEY_5 | KEY_6 | KEY_7 | KEY_8 | KEY_9
+        )
+    }
+}
+
 impl TryFrom<usize> for OsCode {
     type Error = ();
     fn try_from(item: usize) -> Result<Self, Self::Error> {
@@ -1179,11 +1222,18 @@ impl From<KeyValue> for bool {
 pub struct KeyEvent {
     pub code: OsCode,
     pub value: KeyValue,
+    /// Index of the `defdevice` configuration of the input device that the event came from, or
+    /// `None` if the top-level configuration applies.
+    pub device: Option<u8>,
 }
 
 #[cfg(not(all(feature = "interception_driver", target_os = "windows")))]
 impl KeyEvent {
     pub fn new(code: OsCode, value: KeyValue) -> Self {
-        Self { code, value }
+        Self {
+            code,
+            value,
+            device: None,
+        }
     }
 }
@@ -504,6 +504,7 @@ impl TryFrom<InputEvent> for KeyEvent {
                 true => KeyValue::Release,
                 false => KeyValue::Press,
             },
+            device: None,
         })
     }
 }
@@ -1,4 +1,4 @@
-use kanata_keyberon::layout::*;
+use std::ops::{Index, IndexMut};
 
 use crate::cfg::KanataAction;
 use crate::custom_action::*;
@@ -6,27 +6,75 @@ use crate::keys::OsCode;
 
 // OsCode::KEY_MAX is the biggest OsCode
 pub const KEYS_IN_ROW: usize = OsCode::KEY_MAX as usize;
-pub const LAYER_COLUMNS: usize = 2;
-pub const MAX_LAYERS: usize = 25;
-pub const ACTUAL_NUM_LAYERS: usize = MAX_LAYERS * 2;
-
-pub type KanataLayers =
-    Layers<KEYS_IN_ROW, LAYER_COLUMNS, ACTUAL_NUM_LAYERS, &'static [&'static CustomAction]>;
-
-type Row = [kanata_keyberon::action::Action<&'static [&'static CustomAction]>; KEYS_IN_ROW];
-
-pub fn new_layers() -> Box<KanataLayers> {
-    let boxed_slice: Box<[[Row; LAYER_COLUMNS]]> = {
-        let mut layers = Vec::with_capacity(ACTUAL_NUM_LAYERS);
-        for _ in 0..ACTUAL_NUM_LAYERS {
-            layers.push([
-                [KanataAction::Trans; KEYS_IN_ROW],
-                [KanataAction::Trans; KEYS_IN_ROW],
-            ]);
+/// Row of the first `defdevice` block. Row 0 holds the physical keys of `defsrc` and row 1 holds
+/// fake keys and chord outputs.
+pub const DEVICE_ROWS_START: usize = 2;
+/// Maximum number of `defdevice` blocks, each of which gets its own row in every layer. Keyberon
+/// addresses rows with a `u8`, so every row must have an index below 256.
+pub const MAX_DEVICE_CFGS: usize = u8::MAX as usize + 1 - DEVICE_ROWS_START;
+
+pub type Row = [kanata_keyberon::action::Action<&'static [&'static CustomAction]>; KEYS_IN_ROW];
+
+/// The layers of a configuration. Every `deflayer` has one layer, and the last layer holds the
+/// actions of `defsrc` that transparent keys on the default layer fall back to. Each layer has
+/// `DEVICE_ROWS_START` rows plus one row per `defdevice` block, and indexing returns the rows of a
+/// layer.
+pub struct KanataLayers {
+    rows: Box<[Row]>,
+    rows_per_layer: usize,
+}
+
+impl KanataLayers {
+    /// Returns `layer_count` layers for `device_count` devices with only transparent keys.
+    pub fn new(layer_count: usize, device_count: usize) -> Self {
+        let rows_per_layer = DEVICE_ROWS_START + device_count;
+        let mut rows = Vec::with_capacity(layer_count * rows_per_layer);
+        for _ in 0..layer_count * rows_per_layer {
+            rows.push([KanataAction::Trans; KEYS_IN_ROW]);
+        }
+        Self {
+            rows: rows.into_boxed_slice(),
+            rows_per_layer,
         }
-        layers
     }
-    .into_boxed_slice();
-    let ptr = Box::into_raw(boxed_slice) as *mut KanataLayers;
-    unsafe { Box::from_raw(ptr) }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.rows.len() / self.rows_per_layer
+    }
+
+    pub fn rows_per_layer(&self) -> usize {
+        self.rows_per_layer
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[Row]> {
+        self.rows.chunks(self.rows_per_layer)
+    }
+
+    /// Overwrite the layer at index `dest` with a copy of the layer at index `src`.
+    pub fn copy_layer(&mut self, src: usize, dest: usize) {
+        self.rows.copy_within(
+            src * self.rows_per_layer..(src + 1) * self.rows_per_layer,
+            dest * self.rows_per_layer,
+        );
+    }
+
+    /// Returns the rows of all layers, one layer after the other.
+    pub fn into_rows(self) -> Box<[Row]> {
+        self.rows
+    }
+}
+
+impl Index<usize> for KanataLayers {
+    type Output = [Row];
+
+    fn index(&self, layer: usize) -> &[Row] {
+        &self.rows[layer * self.rows_per_layer..(layer + 1) * self.rows_per_layer]
+    }
+}
+
+impl IndexMut<usize> for KanataLayers {
+    fn index_mut(&mut self, layer: usize) -> &mut [Row] {
+        &mut self.rows[layer * self.rows_per_layer..(layer + 1) * self.rows_per_layer]
+    }
 }
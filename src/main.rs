@@ -1,7 +1,8 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use log::info;
 use simplelog::*;
-use std::path::{Path, PathBuf};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 mod cfg;
 mod custom_action;
@@ -18,28 +19,64 @@ use tcp_server::TcpServer;
 type CfgPath = PathBuf;
 
 pub struct ValidatedArgs {
-    path: CfgPath,
-    port: Option<i32>,
+    /// The configurations given to `--cfg`, of which the first is used at startup.
+    paths: Vec<CfgPath>,
+    tcp_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
     #[cfg(target_os = "linux")]
     symlink_path: Option<String>,
+    simulate_path: Option<PathBuf>,
+    check: bool,
+    watch: bool,
+    output: oskbd::OutputBackend,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Configuration file to use with kanata
-    #[clap(short, long, default_value = "kanata.kbd")]
-    cfg: String,
+    /// Configuration files to use with kanata. The first one is used at startup and the
+    /// switch-config action switches to another one by its file name without the extension, e.g.
+    /// `(switch-config work)` for work.kbd.
+    #[clap(short, long, default_value = "kanata.kbd", multiple_values = true)]
+    cfg: Vec<String>,
 
-    /// Port to run the TCP server on
-    #[clap(short, long)]
-    port: Option<i32>,
+    /// Port to run the TCP server on, optionally with the address to bind to, e.g. 1234 or
+    /// 127.0.0.1:1234. Only a port binds to all addresses.
+    #[clap(short, long, value_name = "[ADDRESS:]PORT")]
+    port: Option<String>,
+
+    /// Path of a Unix domain socket to serve the same protocol as the TCP server on. Only the
+    /// user running kanata can connect to it.
+    #[cfg(unix)]
+    #[clap(long, value_name = "PATH")]
+    socket: Option<String>,
 
     /// Path of the symlink pointing to the newly-created device
     #[cfg(target_os = "linux")]
     #[clap(short, long)]
     symlink_path: Option<String>,
 
+    /// Run the configuration against a script of input events instead of real devices and print
+    /// the output events to stdout
+    #[clap(long, value_name = "SCRIPT")]
+    simulate: Option<String>,
+
+    /// Check configuration files for errors without running kanata, instead of using --cfg.
+    /// switch-config actions may switch to any of the files given.
+    #[clap(long, value_name = "CFG", multiple_values = true)]
+    check: Option<Vec<String>>,
+
+    /// Live reload the configuration when the configuration file or a file that it includes
+    /// changes
+    #[clap(short, long)]
+    watch: bool,
+
+    /// Where to send output events: os (the OS output device), log (log them) or
+    /// tcp:<address> (forward them as lines of text to a TCP listener)
+    #[clap(long, default_value = "os")]
+    output: String,
+
     /// Enable debug logging
     #[clap(short, long)]
     debug: bool,
@@ -53,11 +90,16 @@ struct Args {
 fn cli_init() -> Result<ValidatedArgs> {
     let args = Args::parse();
 
-    let cfg_path = Path::new(&args.cfg);
+    let cfg_paths: Vec<CfgPath> = match &args.check {
+        Some(paths) => paths.iter().map(PathBuf::from).collect(),
+        None => args.cfg.iter().map(PathBuf::from).collect(),
+    };
 
     let log_lvl = match (args.debug, args.trace) {
         (_, true) => LevelFilter::Trace,
         (true, false) => LevelFilter::Debug,
+        // Only the check result is of interest when checking.
+        (false, false) if args.check.is_some() => LevelFilter::Warn,
         (false, false) => LevelFilter::Info,
     };
 
@@ -68,29 +110,68 @@ fn cli_init() -> Result<ValidatedArgs> {
     CombinedLogger::init(vec![TermLogger::new(
         log_lvl,
         log_cfg.build(),
-        TerminalMode::Mixed,
+        // Keep stdout free for the simulation and check output.
+        if args.simulate.is_some() || args.check.is_some() {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Mixed
+        },
         ColorChoice::AlwaysAnsi,
     )])
     .expect("logger can init");
     log::info!("kanata v{} starting", env!("CARGO_PKG_VERSION"));
 
-    if !cfg_path.exists() {
-        bail!(
-            "Could not find your config file ({})",
-            cfg_path.to_str().unwrap_or("?")
-        )
+    for (i, cfg_path) in cfg_paths.iter().enumerate() {
+        if !cfg_path.exists() {
+            bail!(
+                "Could not find your config file ({})",
+                cfg_path.to_str().unwrap_or("?")
+            )
+        }
+        let name = kanata::cfg_name(cfg_path);
+        if cfg_paths[..i].iter().any(|p| kanata::cfg_name(p) == name) {
+            bail!("More than one config file is named {name}, which switch-config can't tell apart")
+        }
+    }
+
+    let simulate_path = args.simulate.map(PathBuf::from);
+    if let Some(path) = &simulate_path {
+        if !path.exists() {
+            bail!(
+                "Could not find your simulation file ({})",
+                path.to_str().unwrap_or("?")
+            )
+        }
     }
 
+    let output = args.output.parse()?;
+
+    let tcp_addr = args.port.as_deref().map(parse_tcp_addr).transpose()?;
+
     Ok(ValidatedArgs {
-        path: cfg_path.into(),
-        port: args.port,
+        paths: cfg_paths,
+        tcp_addr,
+        #[cfg(unix)]
+        socket_path: args.socket.map(PathBuf::from),
         #[cfg(target_os = "linux")]
         symlink_path: args.symlink_path,
+        simulate_path,
+        check: args.check.is_some(),
+        watch: args.watch,
+        output,
     })
 }
 
-fn main_impl() -> Result<()> {
-    let args = cli_init()?;
+/// Parse either a port, which binds to all addresses, or an address with a port.
+fn parse_tcp_addr(s: &str) -> Result<SocketAddr> {
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+    s.parse()
+        .map_err(|_| anyhow::anyhow!("invalid TCP server port or address:port: {s}"))
+}
+
+fn main_impl(args: ValidatedArgs) -> Result<()> {
     let kanata_arc = Kanata::new_arc(&args)?;
 
     info!("Sleeping for 2s. Please release all keys and don't press additional ones.");
@@ -102,15 +183,37 @@ fn main_impl() -> Result<()> {
     // events, which it sends to the "processing loop". The processing loop handles keyboard events
     // while also maintaining `tick()` calls to keyberon.
 
-    let (server, ntx, nrx) = if let Some(port) = args.port {
-        let mut server = TcpServer::new(port);
-        server.start(kanata_arc.clone());
+    #[cfg(unix)]
+    let has_socket = args.socket_path.is_some();
+    #[cfg(not(unix))]
+    let has_socket = false;
+    let (server, ntx, nrx) = if args.tcp_addr.is_some() || has_socket {
+        let mut server = TcpServer::new();
+        if let Some(addr) = args.tcp_addr {
+            server.start(addr, kanata_arc.clone())?;
+        }
+        #[cfg(unix)]
+        if let Some(path) = &args.socket_path {
+            server.start_unix(path, kanata_arc.clone())?;
+        }
         let (ntx, nrx) = crossbeam_channel::unbounded();
         (Some(server), Some(ntx), Some(nrx))
     } else {
         (None, None, None)
     };
 
+    if args.watch {
+        Kanata::start_file_watcher(kanata_arc.clone());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let kanata = kanata_arc.clone();
+        oskbd::handle_sighup(move || {
+            info!("received SIGHUP, requesting live reload");
+            kanata.lock().request_live_reload();
+        })?;
+    }
+
     let (tx, rx) = crossbeam_channel::unbounded();
     Kanata::start_processing_loop(kanata_arc.clone(), rx, ntx);
 
@@ -124,7 +227,36 @@ fn main_impl() -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    let ret = main_impl();
+    let args = cli_init().inspect_err(|e| log::error!("main got error `{}`", e))?;
+    if args.check {
+        let mut valid = true;
+        for path in &args.paths {
+            // Parse errors are already formatted with their locations, so print them as-is.
+            let res = cfg::Cfg::new_from_file(path).and_then(|cfg| {
+                kanata::check_cfg(&cfg, &args.paths)
+                    .map_err(|e| anyhow!("{}: {e}", path.to_string_lossy()))
+            });
+            match res {
+                Ok(()) => println!("{}: configuration is valid", path.to_string_lossy()),
+                Err(e) => {
+                    eprintln!("{e}");
+                    valid = false;
+                }
+            }
+        }
+        if !valid {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(script_path) = &args.simulate_path {
+        let ret = Kanata::simulate(&args.paths[0], script_path);
+        if let Err(e) = &ret {
+            log::error!("simulation failed: {e}");
+        }
+        return ret;
+    }
+    let ret = main_impl(args);
     if let Err(e) = ret {
         log::error!("main got error `{}`", &e);
         return Err(e);
@@ -133,3 +265,17 @@ fn main() -> Result<()> {
     let _ = std::io::stdin().read_line(&mut String::new());
     ret
 }
+
+#[test]
+fn parse_tcp_server_addr() {
+    assert_eq!(
+        parse_tcp_addr("1234").unwrap(),
+        "0.0.0.0:1234".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(
+        parse_tcp_addr("127.0.0.1:1234").unwrap(),
+        "127.0.0.1:1234".parse::<SocketAddr>().unwrap()
+    );
+    assert!(parse_tcp_addr("127.0.0.1").is_err());
+    assert!(parse_tcp_addr("localhost:abc").is_err());
+}
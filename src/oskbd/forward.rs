@@ -0,0 +1,69 @@
+//! Contains an output that forwards events to another process over TCP.
+
+use std::io::{self, Write};
+use std::net::TcpStream;
+
+use super::{InputEvent, KeyOutput, OutputEvent};
+use crate::custom_action::*;
+use crate::keys::*;
+
+/// Sends every output event over a TCP connection as a line of text, e.g. `press KEY_A`.
+pub struct TcpForwardOutput {
+    stream: TcpStream,
+}
+
+impl TcpForwardOutput {
+    pub fn connect(addr: &str) -> Result<Self, io::Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    fn send(&mut self, event: OutputEvent) -> Result<(), io::Error> {
+        self.stream.write_all(format!("{event}\n").as_bytes())
+    }
+}
+
+impl KeyOutput for TcpForwardOutput {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+        log::debug!("forwarding output dropping raw event: {event:?}");
+        Ok(())
+    }
+
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+        self.send(OutputEvent::Key(key, value))
+    }
+
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+        self.send(OutputEvent::Unicode(c))
+    }
+
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.send(OutputEvent::ClickBtn(btn))
+    }
+
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.send(OutputEvent::ReleaseBtn(btn))
+    }
+
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+        self.send(OutputEvent::Scroll(direction, distance))
+    }
+}
+
+#[test]
+fn forwards_events_as_lines() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut out = TcpForwardOutput::connect(&addr).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    out.press_key(OsCode::KEY_A).unwrap();
+    out.release_key(OsCode::KEY_A).unwrap();
+    out.send_unicode('🙂').unwrap();
+    drop(out);
+    let lines: Vec<_> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+    assert_eq!(lines, ["press KEY_A", "release KEY_A", "unicode 🙂"]);
+}
@@ -1,41 +1,106 @@
 //! Contains the input/output code for keyboards on Linux.
 
-use evdev::{uinput, Device, EventType, InputEvent, RelativeAxisType};
+pub use evdev::InputEvent;
+use evdev::{uinput, Device, EventType, RelativeAxisType};
 use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
 use signal_hook::{
-    consts::{SIGINT, SIGTERM},
+    consts::{SIGHUP, SIGINT, SIGTERM},
     iterator::Signals,
 };
 
 use std::fs;
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::thread;
 
+use super::KeyOutput;
+use crate::cfg::{DeviceCfg, DeviceMatcher};
 use crate::custom_action::*;
 use crate::keys::KeyEvent;
 use crate::keys::*;
 
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+type HashSet<T> = rustc_hash::FxHashSet<T>;
+
+/// Directories watched for input devices being added. The `by-id` and `by-path` directories are
+/// watched as well because their symlinks, which `linux-dev` often refers to, are created after
+/// the device node itself.
+const WATCHED_DIRS: [&str; 3] = ["/dev/input", "/dev/input/by-id", "/dev/input/by-path"];
+const INOTIFY_TOKEN: Token = Token(usize::MAX);
+
+/// The name and matchers of a `defdevice` configuration.
+struct DeviceMatchers {
+    name: String,
+    matchers: Vec<DeviceMatcher>,
+}
+
+/// A grabbed input device.
+struct InputDevice {
+    device: Device,
+    /// Canonical path of the device, used to avoid grabbing the same device twice.
+    path: PathBuf,
+    /// Index of the `defdevice` configuration the device uses.
+    device_cfg: Option<u8>,
+    /// Keys that are currently held down on the device.
+    pressed: PressedKeys,
+}
+
+/// Tracks the keys held down on a device, so that they can be released if the device is unplugged
+/// while they are held.
+#[derive(Default)]
+struct PressedKeys(HashSet<u16>);
+
+impl PressedKeys {
+    fn update(&mut self, event: &InputEvent) {
+        if event.event_type() != EventType::KEY {
+            return;
+        }
+        match event.value() {
+            0 => {
+                self.0.remove(&event.code());
+            }
+            1 => {
+                self.0.insert(event.code());
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns release events for all keys that are held down.
+    fn into_releases(self) -> Vec<InputEvent> {
+        let mut codes: Vec<u16> = self.0.into_iter().collect();
+        codes.sort_unstable();
+        codes
+            .into_iter()
+            .map(|code| InputEvent::new_now(EventType::KEY, code, KeyValue::Release as i32))
+            .collect()
+    }
+}
 
 pub struct KbdIn {
-    devices: HashMap<Token, Device>,
+    devices: HashMap<Token, InputDevice>,
+    /// Paths from `linux-dev`. If empty, input devices are detected automatically.
+    dev_paths: Vec<String>,
+    device_cfgs: Vec<DeviceMatchers>,
+    inotify: Inotify,
+    next_token: usize,
     poll: Poll,
     events: Events,
 }
 
 impl KbdIn {
-    pub fn new(dev_paths: &[String]) -> Result<Self, io::Error> {
-        let mut device_map = HashMap::default();
+    pub fn new(dev_paths: &[String], device_cfgs: &[DeviceCfg]) -> Result<Self, io::Error> {
         let poll = Poll::new()?;
 
-        let devices = if !dev_paths.is_empty() {
+        let devices: Vec<(PathBuf, Device)> = if !dev_paths.is_empty() {
             dev_paths
                 .iter()
                 .map(|dev_path| (dev_path, Device::open(dev_path)))
                 .filter_map(|(dev_path, open_result)| match open_result {
-                    Ok(d) => Some(d),
+                    Ok(d) => Some((dev_path.into(), d)),
                     Err(e) => {
                         log::warn!("failed to open device '{dev_path}': {e:?}");
                         None
@@ -44,8 +109,7 @@ impl KbdIn {
                 .collect()
         } else {
             let devices: Vec<_> = evdev::enumerate()
-                .map(|(_, device)| device)
-                .filter(is_input_device)
+                .filter(|(_, device)| is_input_device(device))
                 .collect();
             if devices.is_empty() {
                 return Err(io::Error::new(
@@ -61,29 +125,145 @@ impl KbdIn {
                 "No keyboard devices were found",
             ));
         }
-        for (i, mut kbd_in_dev) in devices.into_iter().enumerate() {
-            // NOTE: This grab-ungrab-grab sequence magically fixes an issue with a Lenovo Yoga
-            // trackpad not working. No idea why this works.
-            kbd_in_dev.grab()?;
-            kbd_in_dev.ungrab()?;
-            kbd_in_dev.grab()?;
-
-            let tok = Token(i);
-            let fd = kbd_in_dev.as_raw_fd();
-            poll.registry()
-                .register(&mut SourceFd(&fd), tok, Interest::READABLE)?;
-            device_map.insert(tok, kbd_in_dev);
-        }
 
-        let events = Events::with_capacity(32);
-        Ok(KbdIn {
-            devices: device_map,
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        for dir in WATCHED_DIRS {
+            if let Err(e) =
+                inotify.add_watch(dir, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB)
+            {
+                log::debug!("not watching {dir} for new devices: {e}");
+            }
+        }
+        poll.registry().register(
+            &mut SourceFd(&inotify.as_raw_fd()),
+            INOTIFY_TOKEN,
+            Interest::READABLE,
+        )?;
+
+        let mut kbd_in = KbdIn {
+            devices: HashMap::default(),
+            dev_paths: dev_paths.to_vec(),
+            device_cfgs: device_cfgs
+                .iter()
+                .map(|cfg| DeviceMatchers {
+                    name: cfg.name.clone(),
+                    matchers: cfg.matchers.clone(),
+                })
+                .collect(),
+            inotify,
+            next_token: 0,
             poll,
-            events,
-        })
+            events: Events::with_capacity(32),
+        };
+        for (path, device) in devices {
+            kbd_in.grab_device(&path, device)?;
+        }
+        Ok(kbd_in)
+    }
+
+    /// Grab the device and start reading events from it.
+    fn grab_device(&mut self, path: &Path, mut device: Device) -> Result<(), io::Error> {
+        let device_cfg = device_cfg_idx(path, &device, &self.device_cfgs);
+        if let Some(idx) = device_cfg {
+            log::info!(
+                "device {} uses defdevice {}",
+                device.name().unwrap_or("?"),
+                self.device_cfgs[usize::from(idx)].name
+            );
+        }
+
+        // NOTE: This grab-ungrab-grab sequence magically fixes an issue with a Lenovo Yoga
+        // trackpad not working. No idea why this works.
+        device.grab()?;
+        device.ungrab()?;
+        device.grab()?;
+
+        let tok = Token(self.next_token);
+        self.next_token += 1;
+        let fd = device.as_raw_fd();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&fd), tok, Interest::READABLE)?;
+        self.devices.insert(
+            tok,
+            InputDevice {
+                device,
+                path: fs::canonicalize(path).unwrap_or_else(|_| path.into()),
+                device_cfg,
+                pressed: PressedKeys::default(),
+            },
+        );
+        Ok(())
     }
 
-    pub fn read(&mut self) -> Result<Vec<InputEvent>, io::Error> {
+    /// Stop reading from a device that has been unplugged. Returns release events for the keys
+    /// that were held down on the device so that they do not stay pressed.
+    fn remove_device(&mut self, tok: Token) -> Vec<(InputEvent, Option<u8>)> {
+        let Some(removed) = self.devices.remove(&tok) else {
+            return vec![];
+        };
+        log::info!(
+            "device {} was removed",
+            removed.device.name().unwrap_or("?")
+        );
+        let fd = removed.device.as_raw_fd();
+        if let Err(e) = self.poll.registry().deregister(&mut SourceFd(&fd)) {
+            log::warn!("failed to deregister removed device: {e}");
+        }
+        removed
+            .pressed
+            .into_releases()
+            .into_iter()
+            .map(|ev| (ev, removed.device_cfg))
+            .collect()
+    }
+
+    /// Grab devices that match `linux-dev`, or the input device detection if `linux-dev` is not
+    /// set, and that are not grabbed yet.
+    fn rescan(&mut self) {
+        let is_grabbed = |devices: &HashMap<Token, InputDevice>, path: &Path| {
+            let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+            devices.values().any(|d| d.path == path)
+        };
+        let new_devices: Vec<(PathBuf, Device)> = if !self.dev_paths.is_empty() {
+            self.dev_paths
+                .iter()
+                .map(PathBuf::from)
+                .filter(|path| path.exists() && !is_grabbed(&self.devices, path))
+                .filter_map(|path| match Device::open(&path) {
+                    Ok(d) => Some((path, d)),
+                    Err(e) => {
+                        // Permissions are often not set up yet when the device node is created.
+                        // The watch for attribute changes triggers another rescan once they are.
+                        log::debug!("failed to open new device {path:?}: {e}");
+                        None
+                    }
+                })
+                .collect()
+        } else {
+            evdev::enumerate()
+                .filter(|(path, device)| {
+                    !is_grabbed(&self.devices, path) && is_input_device(device)
+                })
+                .collect()
+        };
+        for (path, device) in new_devices {
+            log::info!(
+                "grabbing new device {} at {path:?}",
+                device.name().unwrap_or("?")
+            );
+            if let Err(e) = self.grab_device(&path, device) {
+                log::warn!("failed to grab new device {path:?}: {e}");
+            }
+        }
+    }
+
+    /// Wait for input events. Each event is returned along with the index of the `defdevice`
+    /// configuration of the device it came from.
+    ///
+    /// Devices that are plugged in while waiting are grabbed and devices that are unplugged are
+    /// dropped.
+    pub fn read(&mut self) -> Result<Vec<(InputEvent, Option<u8>)>, io::Error> {
         let mut input_events = vec![];
         loop {
             log::trace!("polling");
@@ -91,20 +271,42 @@ impl KbdIn {
                 log::error!("failed poll: {:?}", e);
                 return Ok(vec![]);
             }
+            let mut needs_rescan = false;
+            let mut removed_devices = vec![];
             for event in &self.events {
-                if let Some(device) = self.devices.get_mut(&event.token()) {
-                    device
-                        .fetch_events()
-                        .map_err(|e| {
-                            log::error!("failed fetch events");
-                            e
-                        })?
-                        .into_iter()
-                        .for_each(|ev| input_events.push(ev));
-                } else {
+                if event.token() == INOTIFY_TOKEN {
+                    needs_rescan = true;
+                    continue;
+                }
+                let Some(input_device) = self.devices.get_mut(&event.token()) else {
                     panic!("encountered unexpected epoll event {event:?}");
+                };
+                match input_device.device.fetch_events() {
+                    Ok(events) => events.for_each(|ev| {
+                        input_device.pressed.update(&ev);
+                        input_events.push((ev, input_device.device_cfg))
+                    }),
+                    Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => {
+                        removed_devices.push(event.token())
+                    }
+                    Err(e) => {
+                        log::error!("failed fetch events");
+                        return Err(e);
+                    }
                 }
             }
+            for tok in removed_devices {
+                input_events.extend(self.remove_device(tok));
+            }
+            if needs_rescan {
+                // Drain the inotify events; the registration is edge-triggered.
+                while let Ok(events) = self.inotify.read_events() {
+                    if events.is_empty() {
+                        break;
+                    }
+                }
+                self.rescan();
+            }
             if !input_events.is_empty() {
                 return Ok(input_events);
             }
@@ -112,14 +314,34 @@ impl KbdIn {
     }
 }
 
+/// Returns the index of the first `defdevice` configuration that matches the device.
+fn device_cfg_idx(path: &Path, device: &Device, device_cfgs: &[DeviceMatchers]) -> Option<u8> {
+    let canonical_path = fs::canonicalize(path).ok();
+    let id = device.input_id();
+    device_cfgs
+        .iter()
+        .position(|cfg| {
+            cfg.matchers.iter().any(|matcher| match matcher {
+                DeviceMatcher::Name(name) => device.name() == Some(name.as_str()),
+                DeviceMatcher::Path(p) => {
+                    canonical_path.is_some() && fs::canonicalize(p).ok() == canonical_path
+                }
+                DeviceMatcher::Id { vendor, product } => {
+                    id.vendor() == *vendor && id.product() == *product
+                }
+            })
+        })
+        .map(|i| i as u8)
+}
+
 pub fn is_input_device(device: &Device) -> bool {
     use evdev::Key;
     let is_keyboard = device
         .supported_keys()
-        .map_or(false, |keys| keys.contains(Key::KEY_ENTER));
+        .is_some_and(|keys| keys.contains(Key::KEY_ENTER));
     let is_mouse = device
         .supported_relative_axes()
-        .map_or(false, |axes| axes.contains(RelativeAxisType::REL_X));
+        .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X));
     if is_keyboard || is_mouse {
         if device.name() == Some("kanata") {
             return false;
@@ -203,12 +425,48 @@ impl KbdOut {
         })
     }
 
-    pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+    fn do_scroll(
+        &mut self,
+        direction: MWheelDirection,
+        lo_res_distance: u16,
+    ) -> Result<(), io::Error> {
+        let ev = InputEvent::new(
+            EventType::RELATIVE,
+            RelativeAxisType::REL_WHEEL.0,
+            match direction {
+                MWheelDirection::Up => i32::from(lo_res_distance),
+                MWheelDirection::Down => -i32::from(lo_res_distance),
+                _ => unreachable!(), // unreachable based on pub fn scroll
+            },
+        );
+        self.write(ev)
+    }
+
+    fn do_hscroll(
+        &mut self,
+        direction: MWheelDirection,
+        lo_res_distance: u16,
+    ) -> Result<(), io::Error> {
+        let ev = InputEvent::new(
+            EventType::RELATIVE,
+            RelativeAxisType::REL_HWHEEL.0,
+            match direction {
+                MWheelDirection::Right => i32::from(lo_res_distance),
+                MWheelDirection::Left => -i32::from(lo_res_distance),
+                _ => unreachable!(), // unreachable based on pub fn scroll
+            },
+        );
+        self.write(ev)
+    }
+}
+
+impl KeyOutput for KbdOut {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
         self.device.emit(&[event])?;
         Ok(())
     }
 
-    pub fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
         let key_ev = KeyEvent::new(key, value);
         let input_ev = key_ev.into();
         log::debug!("input ev: {:?}", input_ev);
@@ -216,16 +474,8 @@ impl KbdOut {
         Ok(())
     }
 
-    pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Press)
-    }
-
-    pub fn release_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Release)
-    }
-
     /// Send using C-S-u + <unicode hex number> + ret
-    pub fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
         let hex = format!("{:x}", c as u32);
         self.press_key(OsCode::KEY_LEFTCTRL)?;
         self.press_key(OsCode::KEY_LEFTSHIFT)?;
@@ -246,15 +496,15 @@ impl KbdOut {
         Ok(())
     }
 
-    pub fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         self.press_key(btn.into())
     }
 
-    pub fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         self.release_key(btn.into())
     }
 
-    pub fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
         log::debug!("scroll: {direction:?} {distance:?}");
         match direction {
             MWheelDirection::Up | MWheelDirection::Down => {
@@ -289,40 +539,6 @@ impl KbdOut {
 
         Ok(())
     }
-
-    fn do_scroll(
-        &mut self,
-        direction: MWheelDirection,
-        lo_res_distance: u16,
-    ) -> Result<(), io::Error> {
-        let ev = InputEvent::new(
-            EventType::RELATIVE,
-            RelativeAxisType::REL_WHEEL.0,
-            match direction {
-                MWheelDirection::Up => i32::from(lo_res_distance),
-                MWheelDirection::Down => -i32::from(lo_res_distance),
-                _ => unreachable!(), // unreachable based on pub fn scroll
-            },
-        );
-        self.write(ev)
-    }
-
-    fn do_hscroll(
-        &mut self,
-        direction: MWheelDirection,
-        lo_res_distance: u16,
-    ) -> Result<(), io::Error> {
-        let ev = InputEvent::new(
-            EventType::RELATIVE,
-            RelativeAxisType::REL_HWHEEL.0,
-            match direction {
-                MWheelDirection::Right => i32::from(lo_res_distance),
-                MWheelDirection::Left => -i32::from(lo_res_distance),
-                _ => unreachable!(), // unreachable based on pub fn scroll
-            },
-        );
-        self.write(ev)
-    }
 }
 
 impl From<Btn> for OsCode {
@@ -365,20 +581,30 @@ impl Symlink {
     fn clean_when_killed(symlink: Self) {
         thread::spawn(|| {
             let mut signals = Signals::new([SIGINT, SIGTERM]).unwrap();
-            for signal in &mut signals {
-                match signal {
-                    SIGINT | SIGTERM => {
-                        drop(symlink);
-                        signal_hook::low_level::emulate_default_handler(signal).unwrap();
-                        unreachable!();
-                    }
-                    _ => unreachable!(),
+            match signals.forever().next() {
+                Some(signal @ (SIGINT | SIGTERM)) => {
+                    drop(symlink);
+                    signal_hook::low_level::emulate_default_handler(signal).unwrap();
+                    unreachable!();
                 }
+                _ => unreachable!(),
             }
         });
     }
 }
 
+/// Call `on_sighup` in another thread whenever the process receives SIGHUP, instead of
+/// terminating.
+pub fn handle_sighup(on_sighup: impl Fn() + Send + 'static) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            on_sighup();
+        }
+    });
+    Ok(())
+}
+
 pub fn parse_dev_paths(paths: &str) -> Vec<String> {
     let mut all_paths = vec![];
     let mut full_dev_path = String::new();
@@ -397,6 +623,35 @@ pub fn parse_dev_paths(paths: &str) -> Vec<String> {
     all_paths
 }
 
+#[test]
+fn pressed_keys_are_released_on_removal() {
+    let key =
+        |code: OsCode, value: KeyValue| InputEvent::new(EventType::KEY, code as u16, value as i32);
+    let mut pressed = PressedKeys::default();
+    pressed.update(&key(OsCode::KEY_A, KeyValue::Press));
+    pressed.update(&key(OsCode::KEY_B, KeyValue::Press));
+    pressed.update(&key(OsCode::KEY_LEFTSHIFT, KeyValue::Press));
+    pressed.update(&key(OsCode::KEY_B, KeyValue::Repeat));
+    pressed.update(&key(OsCode::KEY_A, KeyValue::Release));
+    pressed.update(&InputEvent::new(
+        EventType::RELATIVE,
+        RelativeAxisType::REL_X.0,
+        1,
+    ));
+    let releases: Vec<_> = pressed
+        .into_releases()
+        .into_iter()
+        .map(|ev| (ev.event_type(), ev.code(), ev.value()))
+        .collect();
+    assert_eq!(
+        releases,
+        vec![
+            (EventType::KEY, OsCode::KEY_LEFTSHIFT as u16, 0),
+            (EventType::KEY, OsCode::KEY_B as u16, 0),
+        ]
+    );
+}
+
 #[test]
 fn test_parse_dev_paths() {
     assert_eq!(parse_dev_paths("h:w"), ["h", "w"]);
@@ -0,0 +1,45 @@
+//! Contains an output that logs events instead of sending them to the OS.
+
+use std::io;
+
+use super::{InputEvent, KeyOutput, OutputEvent};
+use crate::custom_action::*;
+use crate::keys::*;
+
+/// Logs every output event at info level and otherwise drops it.
+#[derive(Debug, Default)]
+pub struct LoggingOutput;
+
+impl LoggingOutput {
+    fn log(&mut self, event: OutputEvent) -> Result<(), io::Error> {
+        log::info!("output: {event}");
+        Ok(())
+    }
+}
+
+impl KeyOutput for LoggingOutput {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+        log::debug!("output: raw event {event:?}");
+        Ok(())
+    }
+
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+        self.log(OutputEvent::Key(key, value))
+    }
+
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+        self.log(OutputEvent::Unicode(c))
+    }
+
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.log(OutputEvent::ClickBtn(btn))
+    }
+
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.log(OutputEvent::ReleaseBtn(btn))
+    }
+
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+        self.log(OutputEvent::Scroll(direction, distance))
+    }
+}
@@ -1,5 +1,13 @@
 //! Platform specific code for low level keyboard read/write.
 
+use std::io;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::custom_action::*;
+use crate::keys::*;
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
@@ -9,3 +17,75 @@ pub use linux::*;
 mod windows;
 #[cfg(target_os = "windows")]
 pub use windows::*;
+
+mod forward;
+mod logging;
+mod simulated;
+pub use forward::*;
+pub use logging::*;
+pub use simulated::*;
+
+/// Selects where kanata sends its output events.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum OutputBackend {
+    /// The OS output device, e.g. uinput on Linux.
+    #[default]
+    Os,
+    /// Log the events instead of sending them anywhere.
+    Log,
+    /// Forward the events to the TCP address, see `TcpForwardOutput`.
+    Tcp(String),
+}
+
+impl FromStr for OutputBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "os" => Ok(Self::Os),
+            "log" => Ok(Self::Log),
+            _ => match s.strip_prefix("tcp:") {
+                Some(addr) if !addr.is_empty() => Ok(Self::Tcp(addr.to_owned())),
+                _ => Err(anyhow!(
+                    "unknown output {s}: expected one of os, log or tcp:<address>"
+                )),
+            },
+        }
+    }
+}
+
+/// A destination for the key and mouse events that kanata produces.
+pub trait KeyOutput: Send {
+    /// Write an OS input event as-is, e.g. for passing through events that kanata does not handle.
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error>;
+
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error>;
+
+    fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
+        self.write_key(key, KeyValue::Press)
+    }
+
+    fn release_key(&mut self, key: OsCode) -> Result<(), io::Error> {
+        self.write_key(key, KeyValue::Release)
+    }
+
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error>;
+
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error>;
+
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error>;
+
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error>;
+}
+
+#[test]
+fn parse_output_backend() {
+    assert_eq!("os".parse::<OutputBackend>().unwrap(), OutputBackend::Os);
+    assert_eq!("log".parse::<OutputBackend>().unwrap(), OutputBackend::Log);
+    assert_eq!(
+        "tcp:127.0.0.1:1234".parse::<OutputBackend>().unwrap(),
+        OutputBackend::Tcp("127.0.0.1:1234".into())
+    );
+    assert!("tcp:".parse::<OutputBackend>().is_err());
+    assert!("uinput".parse::<OutputBackend>().is_err());
+}
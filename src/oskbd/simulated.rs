@@ -0,0 +1,87 @@
+//! Contains an output that records events in memory instead of sending them to the OS.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::{InputEvent, KeyOutput};
+use crate::custom_action::*;
+use crate::keys::*;
+
+/// An event that kanata sent to an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEvent {
+    Key(OsCode, KeyValue),
+    Unicode(char),
+    ClickBtn(Btn),
+    ReleaseBtn(Btn),
+    Scroll(MWheelDirection, u16),
+}
+
+impl fmt::Display for OutputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputEvent::Key(key, KeyValue::Press) => write!(f, "press {key:?}"),
+            OutputEvent::Key(key, KeyValue::Release) => write!(f, "release {key:?}"),
+            OutputEvent::Key(key, KeyValue::Repeat) => write!(f, "repeat {key:?}"),
+            OutputEvent::Unicode(c) => write!(f, "unicode {c}"),
+            OutputEvent::ClickBtn(btn) => write!(f, "click {btn:?}"),
+            OutputEvent::ReleaseBtn(btn) => write!(f, "unclick {btn:?}"),
+            OutputEvent::Scroll(direction, distance) => {
+                write!(f, "scroll {direction:?} {distance}")
+            }
+        }
+    }
+}
+
+/// Records output events in memory. Cloning the output shares the recorded events, so a clone can
+/// be kept around to inspect what was sent after handing the output over to kanata.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedOutput {
+    events: Arc<Mutex<Vec<OutputEvent>>>,
+}
+
+impl SimulatedOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove and return all events recorded so far.
+    pub fn take_events(&self) -> Vec<OutputEvent> {
+        std::mem::take(&mut *self.events.lock())
+    }
+
+    fn push(&mut self, event: OutputEvent) -> Result<(), io::Error> {
+        self.events.lock().push(event);
+        Ok(())
+    }
+}
+
+impl KeyOutput for SimulatedOutput {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+        log::debug!("simulated output dropping raw event: {event:?}");
+        Ok(())
+    }
+
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+        self.push(OutputEvent::Key(key, value))
+    }
+
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+        self.push(OutputEvent::Unicode(c))
+    }
+
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.push(OutputEvent::ClickBtn(btn))
+    }
+
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        self.push(OutputEvent::ReleaseBtn(btn))
+    }
+
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+        self.push(OutputEvent::Scroll(direction, distance))
+    }
+}
@@ -7,6 +7,7 @@ use interception::{KeyState, MouseFlags, MouseState, Stroke};
 
 use crate::custom_action::*;
 use crate::keys::*;
+use crate::oskbd::KeyOutput;
 
 /// Key event received by the low level keyboard hook.
 #[derive(Debug, Clone, Copy)]
@@ -82,40 +83,34 @@ impl KbdOut {
     pub fn new(keys_tx: Sender<InputEvent>) -> Result<Self, io::Error> {
         Ok(Self { keys_tx })
     }
+}
 
-    pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+impl KeyOutput for KbdOut {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
         self.keys_tx.try_send(event).unwrap();
         Ok(())
     }
 
-    pub fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
         let event = InputEvent::from_oscode(key, value);
         self.write(event)
     }
 
-    pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Press)
-    }
-
-    pub fn release_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Release)
-    }
-
-    pub fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         log::debug!("click btn: {:?}", btn);
         let event = InputEvent::from_mouse_btn(btn, false);
         self.keys_tx.send(event).unwrap();
         Ok(())
     }
 
-    pub fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         log::debug!("release btn: {:?}", btn);
         let event = InputEvent::from_mouse_btn(btn, true);
         self.keys_tx.send(event).unwrap();
         Ok(())
     }
 
-    pub fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
         log::debug!("scroll: {direction:?} {distance:?}");
         let event = InputEvent::from_mouse_scroll(direction, distance);
         self.keys_tx.send(event).unwrap();
@@ -123,7 +118,7 @@ impl KbdOut {
     }
 
     /// Send using VK_PACKET
-    pub fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
         super::send_uc(c, false);
         super::send_uc(c, true);
         Ok(())
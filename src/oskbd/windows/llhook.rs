@@ -14,6 +14,7 @@ use winapi::um::winuser::*;
 
 use crate::custom_action::*;
 use crate::keys::*;
+use crate::oskbd::KeyOutput;
 
 type HookFn = dyn FnMut(InputEvent) -> bool;
 
@@ -140,33 +141,27 @@ impl KbdOut {
     pub fn new() -> Result<Self, io::Error> {
         Ok(Self {})
     }
+}
 
-    pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+impl KeyOutput for KbdOut {
+    fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
         send_key(event);
         Ok(())
     }
 
-    pub fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+    fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
         let event = InputEvent::from_oscode(key, value);
         self.write(event)
     }
 
-    pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Press)
-    }
-
-    pub fn release_key(&mut self, key: OsCode) -> Result<(), io::Error> {
-        self.write_key(key, KeyValue::Release)
-    }
-
     /// Send using VK_PACKET
-    pub fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+    fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
         super::send_uc(c, false);
         super::send_uc(c, true);
         Ok(())
     }
 
-    pub fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         log::debug!("click btn: {:?}", btn);
         match btn {
             Btn::Left => send_btn(MOUSEEVENTF_LEFTDOWN),
@@ -178,7 +173,7 @@ impl KbdOut {
         Ok(())
     }
 
-    pub fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+    fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
         log::debug!("release btn: {:?}", btn);
         match btn {
             Btn::Left => send_btn(MOUSEEVENTF_LEFTUP),
@@ -190,7 +185,7 @@ impl KbdOut {
         Ok(())
     }
 
-    pub fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+    fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
         log::debug!("scroll: {direction:?} {distance:?}");
         match direction {
             MWheelDirection::Up | MWheelDirection::Down => scroll(direction, distance),
@@ -1,33 +1,110 @@
+use crate::custom_action::FakeKeyAction;
 use crate::Kanata;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Receiver;
 use net2::TcpStreamExt;
 use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+type HashSet<T> = rustc_hash::FxHashSet<T>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Messages sent by the server. `LayerChange` is a notification sent to every client, the other
+/// notifications are only sent to clients subscribed to their `EventKind`, and the rest are
+/// replies to a single request, carrying the id of the request they reply to.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ServerMessage {
     LayerChange { new: String },
+    KeyOutput { key: String, pressed: bool },
+    KeyRepeat { key: String },
+    UnicodeOutput { text: String },
+    SequenceEnter {},
+    SequenceExit {},
+    SequenceComplete {},
+    ReloadSuccess {},
+    ReloadFailure { msg: String },
+    OneShot { active: bool },
+    CapsWord { active: bool },
+    CurrentLayer { id: Option<u64>, name: String },
+    LayerNames { id: Option<u64>, names: Vec<String> },
+    Ok { id: Option<u64> },
+    Error { id: Option<u64>, msg: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ClientMessage {
     ChangeLayer { new: String },
+    RequestCurrentLayer {},
+    RequestLayerNames {},
+    Reload {},
+    SwitchConfig { name: String },
+    ActOnFakeKey { name: String, action: FakeKeyAction },
+    Subscribe { events: Vec<EventKind> },
+    Unsubscribe { events: Vec<EventKind> },
+}
+
+/// Kinds of notifications that clients can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    /// Keys pressed, repeated and released on the OS output, and unicode characters typed.
+    KeyOutput,
+    /// Entering, exiting and completing sequence mode.
+    Sequence,
+    /// Success or failure of live reloads.
+    Reload,
+    /// One-shot keys becoming active or inactive.
+    OneShot,
+    /// Caps-word turning on or off.
+    CapsWord,
+}
+
+/// A client message along with an optional id chosen by the client, which is echoed back in the
+/// reply, e.g. `{"id":1,"ChangeLayer":{"new":"base"}}`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientRequest {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub msg: ClientMessage,
 }
 
 impl ServerMessage {
+    /// The kind of notification this is, or `None` for messages sent regardless of
+    /// subscriptions.
+    pub fn event_kind(&self) -> Option<EventKind> {
+        match self {
+            ServerMessage::KeyOutput { .. }
+            | ServerMessage::KeyRepeat { .. }
+            | ServerMessage::UnicodeOutput { .. } => Some(EventKind::KeyOutput),
+            ServerMessage::SequenceEnter {}
+            | ServerMessage::SequenceExit {}
+            | ServerMessage::SequenceComplete {} => Some(EventKind::Sequence),
+            ServerMessage::ReloadSuccess {} | ServerMessage::ReloadFailure { .. } => {
+                Some(EventKind::Reload)
+            }
+            ServerMessage::OneShot { .. } => Some(EventKind::OneShot),
+            ServerMessage::CapsWord { .. } => Some(EventKind::CapsWord),
+            _ => None,
+        }
+    }
+
+    /// Serialize the message as a line of JSON, terminated by a newline.
     pub fn as_bytes(&self) -> Result<Vec<u8>> {
-        Ok(serde_json::to_string(self)?.as_bytes().to_vec())
+        let mut msg = serde_json::to_vec(self)?;
+        msg.push(b'\n');
+        Ok(msg)
     }
 }
 
-impl FromStr for ClientMessage {
+impl FromStr for ClientRequest {
     type Err = serde_json::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
@@ -35,29 +112,122 @@ impl FromStr for ClientMessage {
     }
 }
 
+/// The reply to a request. Reloads are done by the processing loop once no keys are held, so the
+/// reply to them is only known after that.
+pub enum Reply {
+    Done(ServerMessage),
+    AfterReload {
+        id: Option<u64>,
+        result: Receiver<Result<(), String>>,
+    },
+}
+
+impl Reply {
+    /// Wait for the reply to be known. This must not be called while holding the lock on kanata,
+    /// since the processing loop needs it to do the reload.
+    pub fn wait(self) -> ServerMessage {
+        match self {
+            Reply::Done(msg) => msg,
+            Reply::AfterReload { id, result } => match result.recv() {
+                Ok(Ok(())) => ServerMessage::Ok { id },
+                Ok(Err(msg)) => ServerMessage::Error { id, msg },
+                Err(_) => ServerMessage::Error {
+                    id,
+                    msg: "kanata stopped before reloading".into(),
+                },
+            },
+        }
+    }
+}
+
+impl ClientRequest {
+    /// Act on the request and return the reply to send to the client.
+    pub fn handle(self, kanata: &mut Kanata) -> Reply {
+        let id = self.id;
+        let res = match self.msg {
+            ClientMessage::ChangeLayer { new } => kanata.change_layer(new),
+            ClientMessage::RequestCurrentLayer {} => {
+                return Reply::Done(ServerMessage::CurrentLayer {
+                    id,
+                    name: kanata.current_layer_name(),
+                })
+            }
+            ClientMessage::RequestLayerNames {} => {
+                return Reply::Done(ServerMessage::LayerNames {
+                    id,
+                    names: kanata.layer_names(),
+                })
+            }
+            ClientMessage::Reload {} => {
+                return Reply::AfterReload {
+                    id,
+                    result: kanata.request_live_reload_result(),
+                }
+            }
+            ClientMessage::SwitchConfig { name } => match kanata.request_switch_config(&name) {
+                Ok(result) => return Reply::AfterReload { id, result },
+                Err(e) => Err(e),
+            },
+            ClientMessage::ActOnFakeKey { name, action } => {
+                kanata.handle_fake_key_action(&name, action)
+            }
+            ClientMessage::Subscribe { .. } | ClientMessage::Unsubscribe { .. } => {
+                Err(anyhow!("subscriptions are only supported by the server"))
+            }
+        };
+        Reply::Done(match res {
+            Ok(()) => ServerMessage::Ok { id },
+            Err(e) => ServerMessage::Error {
+                id,
+                msg: e.to_string(),
+            },
+        })
+    }
+}
+
+/// A connected client.
+pub struct Client {
+    /// Replies and notifications are written through the same writer so that they don't
+    /// interleave. It has its own lock so that writing to a slow client doesn't block the others.
+    pub writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    pub subscriptions: HashSet<EventKind>,
+}
+
+impl Client {
+    /// Whether the client should be sent the notification.
+    pub fn wants(&self, msg: &ServerMessage) -> bool {
+        msg.event_kind()
+            .is_none_or(|kind| self.subscriptions.contains(&kind))
+    }
+}
+
+/// Every connected client, by client address.
+pub type Connections = Arc<Mutex<HashMap<String, Client>>>;
+
+/// Serves the JSON protocol to clients connected over TCP and, on Unix, over a Unix domain socket.
 pub struct TcpServer {
-    pub port: i32,
-    pub connections: Arc<Mutex<HashMap<String, TcpStream>>>,
+    pub connections: Connections,
 }
 
 impl TcpServer {
-    pub fn new(port: i32) -> Self {
+    pub fn new() -> Self {
         Self {
-            port,
             connections: Arc::new(Mutex::new(HashMap::default())),
         }
     }
 
-    pub fn start(&mut self, kanata: Arc<Mutex<Kanata>>) {
-        let listener =
-            TcpListener::bind(format!("0.0.0.0:{}", self.port)).expect("TCP server starts");
+    /// Listen for TCP clients at `addr`.
+    pub fn start(&mut self, addr: SocketAddr, kanata: Arc<Mutex<Kanata>>) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| anyhow!("could not start the TCP server at {addr}: {e}"))?;
+        log::info!("TCP server listening at {addr}");
 
         let connections = self.connections.clone();
 
         std::thread::spawn(move || {
             for stream in listener.incoming() {
                 match stream {
-                    Ok(mut stream) => {
+                    Ok(stream) => {
                         stream
                             .set_keepalive(Some(Duration::from_secs(30)))
                             .expect("TCP keepalive is set");
@@ -66,53 +236,448 @@ impl TcpServer {
                             .peer_addr()
                             .expect("incoming conn has known address")
                             .to_string();
+                        let writer = stream.try_clone().expect("stream is clonable");
+                        serve_client(stream, writer, addr, &connections, &kanata);
+                    }
+                    Err(_) => log::error!("not able to accept client connection"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Listen for clients on a Unix domain socket at `path`. The socket is only accessible by the
+    /// user running kanata.
+    #[cfg(unix)]
+    pub fn start_unix(&mut self, path: &Path, kanata: Arc<Mutex<Kanata>>) -> Result<()> {
+        use std::fs::{DirBuilder, Permissions};
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+        use std::os::unix::net::{UnixListener, UnixStream};
 
-                        connections.lock().insert(
-                            addr.clone(),
-                            stream.try_clone().expect("stream is clonable"),
-                        );
-
-                        log::info!("listening for incoming messages {}", &addr);
-
-                        let connections = connections.clone();
-                        let kanata = kanata.clone();
-                        std::thread::spawn(move || loop {
-                            let mut buf = vec![0; 1024];
-                            match stream.read(&mut buf) {
-                                Ok(size) => {
-                                    if let Ok(event) = ClientMessage::from_str(
-                                        &String::from_utf8_lossy(&buf[..size]),
-                                    ) {
-                                        match event {
-                                            ClientMessage::ChangeLayer { new } => {
-                                                kanata.lock().change_layer(new);
-                                            }
-                                        }
-                                    } else {
-                                        log::warn!(
-                                            "client sent an invalid message of size {size}, disconnecting them"
-                                        );
-                                        // Ignore write result because we're about to disconnect
-                                        // the client anyway.
-                                        let _ = stream.write(
-                                            "you sent an invalid message; disconnecting you"
-                                                .as_bytes(),
-                                        );
-                                        connections.lock().remove(&addr);
-                                        break;
-                                    }
-                                }
-                                Err(_) => {
-                                    log::warn!("removing disconnected tcp client: {addr}");
-                                    connections.lock().remove(&addr);
-                                    break;
-                                }
-                            }
-                        });
+        // A socket left behind by a previous run would make binding fail. Only remove it if
+        // nothing is listening on it anymore.
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+            match UnixStream::connect(path) {
+                Ok(_) => anyhow::bail!("{} is in use by another process", path.display()),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => anyhow::bail!("could not check the socket {}: {e}", path.display()),
+            }
+        }
+        // Create the socket in a directory that only the owner can access and restrict its
+        // permissions before moving it into place, so that other users can't connect in between.
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_dir = path.with_file_name(format!(".{file_name}.tmp"));
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&tmp_dir)
+            .map_err(|e| anyhow!("could not create the directory {}: {e}", tmp_dir.display()))?;
+        let tmp_path = tmp_dir.join("socket");
+        let listener = UnixListener::bind(&tmp_path).and_then(|listener| {
+            std::fs::set_permissions(&tmp_path, Permissions::from_mode(0o600))?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        });
+        let _ = std::fs::remove_file(&tmp_path);
+        let _ = std::fs::remove_dir(&tmp_dir);
+        let listener =
+            listener.map_err(|e| anyhow!("could not create the socket {}: {e}", path.display()))?;
+        log::info!("listening on the socket {}", path.display());
+
+        let connections = self.connections.clone();
+        let path = path.display().to_string();
+
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                match stream {
+                    Ok(stream) => {
+                        let addr = format!("{path}#{i}");
+                        let writer = stream.try_clone().expect("stream is clonable");
+                        serve_client(stream, writer, addr, &connections, &kanata);
                     }
                     Err(_) => log::error!("not able to accept client connection"),
                 }
             }
         });
+        Ok(())
+    }
+}
+
+/// Register the client for notifications and handle its requests in a new thread.
+fn serve_client(
+    stream: impl Read + Send + 'static,
+    writer: impl Write + Send + 'static,
+    addr: String,
+    connections: &Connections,
+    kanata: &Arc<Mutex<Kanata>>,
+) {
+    connections.lock().insert(
+        addr.clone(),
+        Client {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            subscriptions: HashSet::default(),
+        },
+    );
+
+    log::info!("listening for incoming messages {}", &addr);
+
+    let connections = connections.clone();
+    let kanata = kanata.clone();
+    std::thread::spawn(move || {
+        let mut reader = MessageReader::new(stream);
+        loop {
+            let reply = match reader.read_message::<ClientRequest>() {
+                Ok(Some(Ok(ClientRequest {
+                    id,
+                    msg: ClientMessage::Subscribe { events },
+                }))) => {
+                    if let Some(client) = connections.lock().get_mut(&addr) {
+                        client.subscriptions.extend(events);
+                    }
+                    ServerMessage::Ok { id }
+                }
+                Ok(Some(Ok(ClientRequest {
+                    id,
+                    msg: ClientMessage::Unsubscribe { events },
+                }))) => {
+                    if let Some(client) = connections.lock().get_mut(&addr) {
+                        client.subscriptions.retain(|kind| !events.contains(kind));
+                    }
+                    ServerMessage::Ok { id }
+                }
+                Ok(Some(Ok(req))) => {
+                    let reply = req.handle(&mut kanata.lock());
+                    reply.wait()
+                }
+                Ok(Some(Err(e))) => {
+                    log::warn!("client {addr} sent an invalid message: {e}");
+                    ServerMessage::Error {
+                        id: None,
+                        msg: format!("invalid message: {e}"),
+                    }
+                }
+                Ok(None) | Err(_) => {
+                    log::warn!("removing disconnected client: {addr}");
+                    connections.lock().remove(&addr);
+                    break;
+                }
+            };
+            // Write through the registered writer so that replies and notifications sent to the
+            // same client don't interleave, without holding the lock of every connection.
+            let Some(writer) = connections.lock().get(&addr).map(|c| c.writer.clone()) else {
+                break;
+            };
+            let res = reply
+                .as_bytes()
+                .and_then(|reply| writer.lock().write_all(&reply).map_err(|e| e.into()));
+            if let Err(e) = res {
+                log::warn!("removing client {addr} after failed reply: {e}");
+                connections.lock().remove(&addr);
+                break;
+            }
+        }
+    });
+}
+
+/// Maximum length of a single message. A client sending longer lines is disconnected.
+const MAX_MESSAGE_LEN: u64 = 64 * 1024;
+
+/// Decodes newline-delimited JSON messages from a stream. A message may arrive split across
+/// several reads and several messages may arrive in a single read.
+pub struct MessageReader<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+        }
+    }
+
+    /// Read the next message, skipping empty lines. Returns `Ok(None)` once the stream ends and
+    /// an inner error for a line that is not a valid message, including lines that are not valid
+    /// UTF-8, after which reading can continue.
+    pub fn read_message<T: DeserializeOwned>(
+        &mut self,
+    ) -> io::Result<Option<serde_json::Result<T>>> {
+        loop {
+            self.line.clear();
+            let size = (&mut self.reader)
+                .take(MAX_MESSAGE_LEN + 1)
+                .read_until(b'\n', &mut self.line)?;
+            if size == 0 {
+                return Ok(None);
+            }
+            if !self.line.ends_with(b"\n") && size as u64 > MAX_MESSAGE_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message is too long",
+                ));
+            }
+            let line = self.line.trim_ascii();
+            if !line.is_empty() {
+                return Ok(Some(serde_json::from_slice(line)));
+            }
+        }
     }
 }
+
+#[test]
+fn parse_client_requests() {
+    assert_eq!(
+        ClientRequest::from_str(r#"{"id":3,"ChangeLayer":{"new":"other"}}"#).unwrap(),
+        ClientRequest {
+            id: Some(3),
+            msg: ClientMessage::ChangeLayer {
+                new: "other".into()
+            },
+        }
+    );
+    assert_eq!(
+        ClientRequest::from_str(r#"{"ActOnFakeKey":{"name":"fk1","action":"Tap"}}"#).unwrap(),
+        ClientRequest {
+            id: None,
+            msg: ClientMessage::ActOnFakeKey {
+                name: "fk1".into(),
+                action: FakeKeyAction::Tap,
+            },
+        }
+    );
+    assert!(ClientRequest::from_str(r#"{"id":1,"Unknown":{}}"#).is_err());
+    assert_eq!(
+        String::from_utf8(ServerMessage::Ok { id: Some(3) }.as_bytes().unwrap()).unwrap(),
+        "{\"Ok\":{\"id\":3}}\n"
+    );
+}
+
+#[test]
+fn handle_client_requests() {
+    use crate::cfg;
+    use crate::keys::{KeyValue, OsCode};
+    use crate::oskbd::{OutputEvent, SimulatedOutput};
+
+    let cfg_path = std::path::PathBuf::from("./test_cfgs/chords.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let output = SimulatedOutput::new();
+    let mut kanata = Kanata::new_with_output(cfg_path, cfg, Box::new(output.clone())).unwrap();
+    let handle =
+        |kanata: &mut Kanata, req: &str| ClientRequest::from_str(req).unwrap().handle(kanata);
+
+    assert_eq!(
+        handle(&mut kanata, r#"{"id":1,"RequestLayerNames":{}}"#).wait(),
+        ServerMessage::LayerNames {
+            id: Some(1),
+            names: vec!["base".into(), "other".into()],
+        }
+    );
+    assert_eq!(
+        handle(&mut kanata, r#"{"id":2,"ChangeLayer":{"new":"other"}}"#).wait(),
+        ServerMessage::Ok { id: Some(2) }
+    );
+    assert_eq!(
+        handle(&mut kanata, r#"{"id":3,"RequestCurrentLayer":{}}"#).wait(),
+        ServerMessage::CurrentLayer {
+            id: Some(3),
+            name: "other".into(),
+        }
+    );
+    assert!(matches!(
+        handle(&mut kanata, r#"{"id":4,"ChangeLayer":{"new":"nope"}}"#).wait(),
+        ServerMessage::Error { id: Some(4), .. }
+    ));
+    assert!(matches!(
+        handle(
+            &mut kanata,
+            r#"{"id":5,"ActOnFakeKey":{"name":"nope","action":"Tap"}}"#
+        )
+        .wait(),
+        ServerMessage::Error { id: Some(5), .. }
+    ));
+    // Reloads are replied to once the processing loop did them.
+    let reply = handle(&mut kanata, r#"{"id":6,"Reload":{}}"#);
+    kanata.tick_ms(1, &None).unwrap();
+    assert_eq!(reply.wait(), ServerMessage::Ok { id: Some(6) });
+    assert_eq!(
+        handle(
+            &mut kanata,
+            r#"{"id":7,"ActOnFakeKey":{"name":"fk1","action":"Tap"}}"#
+        )
+        .wait(),
+        ServerMessage::Ok { id: Some(7) }
+    );
+
+    for _ in 0..5 {
+        kanata.tick_ms(1, &None).unwrap();
+    }
+    assert_eq!(
+        output.take_events(),
+        vec![
+            OutputEvent::Key(OsCode::KEY_A, KeyValue::Press),
+            OutputEvent::Key(OsCode::KEY_A, KeyValue::Release),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_unix_socket() {
+    use crate::cfg;
+    use crate::oskbd::SimulatedOutput;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let cfg_path = std::path::PathBuf::from("./test_cfgs/chords.kbd");
+    let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+    let kanata = Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+    let kanata = Arc::new(Mutex::new(kanata));
+    // The processing loop does the reload requested below.
+    let (_kev_tx, kev_rx) = crossbeam_channel::unbounded();
+    Kanata::start_processing_loop(kanata.clone(), kev_rx, None);
+    let path = std::env::temp_dir().join(format!("kanata-test-{}.sock", std::process::id()));
+    let mut server = TcpServer::new();
+    server.start_unix(&path, kanata).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let tmp_dir = path.with_file_name(format!(".kanata-test-{}.sock.tmp", std::process::id()));
+    assert!(!tmp_dir.exists());
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    // Several requests in one write, with the last one split across writes.
+    client
+        .write_all(b"{\"id\":9,\"RequestCurrentLayer\":{}}\n\nnot json\n\xff\xfe\n{\"id\":10,")
+        .unwrap();
+    client.write_all(b"\"Reload\":{}}\n").unwrap();
+    let mut replies = MessageReader::new(client.try_clone().unwrap());
+    let mut next_reply = || {
+        replies
+            .read_message::<ServerMessage>()
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(
+        next_reply(),
+        ServerMessage::CurrentLayer {
+            id: Some(9),
+            name: "base".into(),
+        }
+    );
+    assert!(matches!(
+        next_reply(),
+        ServerMessage::Error { id: None, .. }
+    ));
+    // Input that is not UTF-8 gets an error reply instead of disconnecting the client.
+    assert!(matches!(
+        next_reply(),
+        ServerMessage::Error { id: None, .. }
+    ));
+    assert_eq!(next_reply(), ServerMessage::Ok { id: Some(10) });
+    drop(client);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(unix)]
+#[test]
+fn replace_only_stale_sockets() {
+    use crate::cfg;
+    use crate::oskbd::SimulatedOutput;
+    use std::os::unix::net::UnixListener;
+
+    let new_kanata = || {
+        let cfg_path = std::path::PathBuf::from("./test_cfgs/chords.kbd");
+        let cfg = cfg::Cfg::new_from_file(&cfg_path).unwrap();
+        let kanata =
+            Kanata::new_with_output(cfg_path, cfg, Box::new(SimulatedOutput::new())).unwrap();
+        Arc::new(Mutex::new(kanata))
+    };
+    let path = std::env::temp_dir().join(format!("kanata-test-stale-{}", std::process::id()));
+
+    // Files that are not sockets are left alone.
+    std::fs::write(&path, "data").unwrap();
+    assert!(TcpServer::new().start_unix(&path, new_kanata()).is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    std::fs::remove_file(&path).unwrap();
+
+    // Sockets that something listens on are left alone.
+    let listener = UnixListener::bind(&path).unwrap();
+    assert!(TcpServer::new().start_unix(&path, new_kanata()).is_err());
+
+    // Sockets that nothing listens on anymore are replaced.
+    drop(listener);
+    assert!(path.exists());
+    TcpServer::new().start_unix(&path, new_kanata()).unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn read_split_and_coalesced_messages() {
+    /// Returns the data in chunks of at most 5 bytes.
+    struct Chunked<'a>(&'a [u8]);
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.0.len().min(buf.len()).min(5);
+            buf[..size].copy_from_slice(&self.0[..size]);
+            self.0 = &self.0[size..];
+            Ok(size)
+        }
+    }
+
+    let data = b"{\"RequestLayerNames\":{}}\n{\"id\":2,\"Reload\":{}}\r\n\n{\"id\":3,\"Bad\":{}}\n";
+    let mut reader = MessageReader::new(Chunked(data));
+    let mut next = || reader.read_message::<ClientRequest>().unwrap();
+    assert_eq!(
+        next().unwrap().unwrap(),
+        ClientRequest {
+            id: None,
+            msg: ClientMessage::RequestLayerNames {},
+        }
+    );
+    assert_eq!(
+        next().unwrap().unwrap(),
+        ClientRequest {
+            id: Some(2),
+            msg: ClientMessage::Reload {},
+        }
+    );
+    assert!(next().unwrap().is_err());
+    assert!(next().is_none());
+
+    let long = vec![b'a'; MAX_MESSAGE_LEN as usize + 10];
+    let mut reader = MessageReader::new(&long[..]);
+    assert!(reader.read_message::<ClientRequest>().is_err());
+}
+
+#[test]
+fn clients_get_subscribed_notifications() {
+    let mut client = Client {
+        writer: Arc::new(Mutex::new(Box::new(io::sink()))),
+        subscriptions: HashSet::default(),
+    };
+    let layer = ServerMessage::LayerChange { new: "base".into() };
+    let key = ServerMessage::KeyOutput {
+        key: "KEY_A".into(),
+        pressed: true,
+    };
+    let reload = ServerMessage::ReloadSuccess {};
+    assert!(client.wants(&layer));
+    assert!(!client.wants(&key));
+    client.subscriptions.insert(EventKind::KeyOutput);
+    assert!(client.wants(&key));
+    assert!(!client.wants(&reload));
+    assert_eq!(
+        ClientRequest::from_str(r#"{"Subscribe":{"events":["KeyOutput","Reload"]}}"#).unwrap(),
+        ClientRequest {
+            id: None,
+            msg: ClientMessage::Subscribe {
+                events: vec![EventKind::KeyOutput, EventKind::Reload],
+            },
+        }
+    );
+}